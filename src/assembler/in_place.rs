@@ -0,0 +1,191 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use log::{debug, info};
+
+use crate::chunker;
+use crate::index::ChunkData;
+use crate::digest::ChunkDigest;
+use crate::progress::{Progress, ChunkSource};
+use crate::store;
+
+// Where the data of a chunk written in place comes from
+enum Source {
+    // Offset of the same chunk in the current content of the file
+    File(u64),
+    Store,
+    // Read from the file early because its place gets overwritten first
+    Buffered(Vec<u8>)
+}
+
+struct Op {
+    chunk: ChunkData,
+    source: Source
+}
+
+// Updates file in place to the chunks of an index, using its current content as seed. Chunks
+// already at their offset are left alone, chunks which moved are copied within the file and
+// the rest is fetched from the store. The file is chunked with the min, avg and max chunk size
+// of the index, so its chunks line up with the index ones. Copies are ordered so no chunk is
+// overwritten before it was copied, only chunks moving in a cycle are buffered in memory.
+// Returns how many chunks were written.
+pub fn update_in_place(file: &File, chunks: &[ChunkData], chunk_sizes: (u64, u64, u64), digest: ChunkDigest, store: &mut Box<dyn store::Store>, is_device: bool, progress: &mut Box<dyn Progress>) -> u64 {
+    let current_size = if is_device {
+        chunks.last().map_or(0, |c| c.start + c.size)
+    } else {
+        file.metadata().expect("Error: Cannot read output size").len()
+    };
+    let (min_size, avg_size, max_size) = chunk_sizes;
+    let current = chunker::find_chunk_ids(file, current_size, min_size, max_size, avg_size, digest);
+    let in_place: HashSet<(u64, [u8;32])> = current.iter().map(|c| (c.start, c.id)).collect();
+    let wanted: HashSet<(u64, [u8;32])> = chunks.iter().map(|c| (c.start, c.id)).collect();
+
+    // Any copy of a chunk will do, one which stays in place is never overwritten
+    let mut offsets: HashMap<[u8;32], u64> = HashMap::new();
+    for c in current.iter() {
+        let stays = wanted.contains(&(c.start, c.id));
+        if stays || !offsets.contains_key(&c.id) {
+            offsets.insert(c.id, c.start);
+        }
+    }
+
+    let mut ops: Vec<Op> = chunks.iter()
+        .filter(|c| !in_place.contains(&(c.start, c.id)))
+        .map(|c| Op {
+            chunk: c.clone(),
+            source: match offsets.get(&c.id) {
+                Some(offset) => Source::File(*offset),
+                None => Source::Store
+            }
+        }).collect();
+    info!("{} of {} chunks are in place, {} have to be written", chunks.len() - ops.len(), chunks.len(), ops.len());
+    for c in chunks.iter().filter(|c| in_place.contains(&(c.start, c.id))) {
+        progress.chunk_done(c.size, ChunkSource::Output);
+    }
+
+    // blocks[j] lists the ops writing over the data op j copies, they have to wait for it
+    let mut by_dest: Vec<(u64, u64, usize)> = ops.iter().enumerate().map(|(i, op)| (op.chunk.start, op.chunk.start + op.chunk.size, i)).collect();
+    by_dest.sort();
+    let mut blocks: Vec<Vec<usize>> = vec![Vec::new(); ops.len()];
+    let mut waiting: Vec<usize> = vec![0; ops.len()];
+    for (j, op) in ops.iter().enumerate() {
+        if let Source::File(offset) = op.source {
+            let end = offset + op.chunk.size;
+            // Destinations are disjoint and sorted, so only a few around offset can overlap
+            let first = by_dest.partition_point(|d| d.1 <= offset);
+            for d in by_dest[first..].iter().take_while(|d| d.0 < end) {
+                if d.2 != j {
+                    blocks[j].push(d.2);
+                    waiting[d.2] += 1;
+                }
+            }
+        }
+    }
+
+    let mut done = vec![false; ops.len()];
+    let mut ready: Vec<usize> = (0..ops.len()).filter(|i| waiting[*i] == 0).collect();
+    let mut written = 0;
+    let mut buffered = 0;
+    while written < ops.len() as u64 {
+        let i = match ready.pop() {
+            Some(i) => i,
+            None => {
+                // Every op left waits on another, buffer one copy to break the cycle
+                let j = (0..ops.len()).find(|j| !done[*j] && !blocks[*j].is_empty()).expect("Error: In place update is stuck");
+                debug!("Buffering chunk at {} to break a cycle", ops[j].chunk.start);
+                if let Source::File(offset) = ops[j].source {
+                    ops[j].source = Source::Buffered(read_at(file, offset, ops[j].chunk.size));
+                }
+                release(j, &mut blocks, &mut waiting, &mut ready);
+                buffered += 1;
+                continue;
+            }
+        };
+        let source = match ops[i].source {
+            Source::Store => ChunkSource::Store,
+            _ => ChunkSource::Seed
+        };
+        let data = match &ops[i].source {
+            Source::File(offset) => read_at(file, *offset, ops[i].chunk.size),
+            Source::Buffered(data) => data.clone(),
+            Source::Store => store.read_item(ops[i].chunk.id.to_vec())
+        };
+        if data.len() as u64 != ops[i].chunk.size {
+            panic!("Chunk at {} has {} bytes, expected {}", ops[i].chunk.start, data.len(), ops[i].chunk.size);
+        }
+        file.write_all_at(&data, ops[i].chunk.start).expect("Error: Cannot write chunk to output");
+        progress.chunk_done(ops[i].chunk.size, source);
+        done[i] = true;
+        written += 1;
+        release(i, &mut blocks, &mut waiting, &mut ready);
+    }
+    if buffered > 0 {
+        info!("Buffered {} chunks moving in cycles", buffered);
+    }
+
+    if !is_device {
+        let final_size = chunks.last().map_or(0, |c| c.start + c.size);
+        file.set_len(final_size).expect("Error: Cannot resize output");
+    }
+    written
+}
+
+// Op j copied its data, ops writing over its source may go ahead
+fn release(j: usize, blocks: &mut [Vec<usize>], waiting: &mut [usize], ready: &mut Vec<usize>) {
+    for i in blocks[j].drain(..) {
+        waiting[i] -= 1;
+        if waiting[i] == 0 {
+            ready.push(i);
+        }
+    }
+}
+
+fn read_at(file: &File, offset: u64, size: u64) -> Vec<u8> {
+    let mut buf = vec![0; size as usize];
+    file.read_exact_at(&mut buf, offset).expect("Error: Cannot read chunk from output");
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::chunker::tests::random_bytes;
+    use crate::progress::{Counter, NoProgress};
+    use crate::store::Store;
+
+    #[test]
+    fn file_is_chunked_with_the_index_chunk_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let sizes = (4 * 1024, 16 * 1024, 64 * 1024);
+        let data = random_bytes(1_000_000);
+        let blob_path = dir.path().join("blob");
+        std::fs::write(&blob_path, &data).unwrap();
+        let digest = ChunkDigest::default();
+        let chunks = chunker::find_chunk_ids(&File::open(&blob_path).unwrap(), data.len() as u64, sizes.0, sizes.2, sizes.1, digest);
+        assert!(chunks.len() > 30);
+
+        let store_path = dir.path().join("store");
+        let mut local = store::LocalStore::new(store_path.to_str().unwrap(), 0, 0, 0);
+        local.compression = store::ChunkCompression::Uncompressed;
+        for c in chunks.iter() {
+            local.write_item(data[c.start as usize..(c.start + c.size) as usize].to_vec());
+        }
+        let mut store: Box<dyn store::Store> = Box::new(local);
+
+        // Same content shifted by a few bytes, only the chunks around the change are missing
+        let output_path = dir.path().join("output");
+        let mut output = File::create(&output_path).unwrap();
+        output.write_all(b"inserted").unwrap();
+        output.write_all(&data).unwrap();
+        drop(output);
+        let output = std::fs::OpenOptions::new().read(true).write(true).open(&output_path).unwrap();
+        let (counter, counts) = Counter::new(Box::new(NoProgress));
+        let mut progress: Box<dyn Progress> = Box::new(counter);
+        update_in_place(&output, &chunks, sizes, digest, &mut store, false, &mut progress);
+
+        assert_eq!(std::fs::read(&output_path).unwrap(), data);
+        let store_bytes = counts.lock().unwrap().store_bytes;
+        assert!(store_bytes <= 2 * sizes.2, "{} bytes came from the store", store_bytes);
+    }
+}
@@ -1,18 +1,39 @@
+use crate::chunker;
 use crate::io as local_io;
 use crate::index;
+use crate::reader::{self, ChunkCache};
 use crate::seed;
 use crate::store;
 
-use log::{debug, info};
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
 use std::rc::Rc;
+use crate::digest::ChunkDigest;
+use crate::progress::{self, ChunkSource};
+
+mod in_place;
+mod parallel;
+mod verify;
+pub use self::verify::{find_bad_chunks, repair};
 
 // AssemblerConfig 
 pub struct AssemblerConfig {
     pub seed: Option<seed::LocalSeedFile>,
-    pub seed_index: Option<Box<index::Index>>,
-    pub store: Box<store::Store>,
-    pub new_index: Box<index::Index>,
-    pub output: Box<local_io::LocalOutputFile>
+    pub seed_index: Option<Box<dyn index::Index>>,
+    pub store: Box<dyn store::Store>,
+    // Extra stores for parallel workers fetching and writing chunks, empty to assemble in order
+    pub worker_stores: Vec<Box<dyn store::Store>>,
+    pub new_index: Box<dyn index::Index>,
+    pub output: Box<local_io::LocalOutputFile>,
+    // Leave chunks alone which the output already holds, saves writes and downloads on devices
+    pub skip_matching: bool,
+    // Use the current output as seed and only rewrite what changed, without a second copy
+    pub in_place: bool,
+    // Keep the chunks a previous, interrupted extract wrote and continue after them
+    pub resume: bool,
+    pub progress: Box<dyn progress::Progress>
     // Add store here
 }
 
@@ -22,53 +43,478 @@ pub trait AssembleOps {
 
 impl AssembleOps for AssemblerConfig {
     fn assemble(&mut self) {
+        if self.output.stream {
+            if self.in_place || self.resume || self.skip_matching || !self.worker_stores.is_empty() {
+                info!("Output can't seek, writing chunks in order");
+            }
+            let file = self.output.file.clone();
+            self.assemble_to(&mut &*file);
+            return;
+        }
         // Read the index file 
         self.new_index.read();
 
-        let chunks_updated = self.new_index.getChunkData();
-        let out_file = Rc::get_mut(&mut self.output.file).unwrap();
+        let chunks_updated = self.new_index.get_chunk_data();
+        let digest = self.new_index.digest();
+        let total_size = chunks_updated.last().map_or(0, |c| c.start + c.size);
+        if let Some(device_size) = self.output.device_size {
+            if total_size > device_size {
+                panic!("Output device of {} bytes is too small for {} bytes", device_size, total_size);
+            }
+        }
+        self.progress.start(total_size);
+        if self.in_place {
+            info!("Started assembling in place");
+            let written = in_place::update_in_place(&self.output.file, &chunks_updated, self.new_index.chunk_sizes(), digest, &mut self.store, self.output.device_size.is_some(), &mut self.progress);
+            self.progress.finish();
+            info!("Wrote {} of {} chunks", written, chunks_updated.len());
+            if self.output.device_size.is_some() {
+                self.output.file.sync_all().expect("Error: Cannot sync output device");
+            }
+            return;
+        }
+        let mut skipped = 0;
+        let mut first = 0;
+        if self.resume {
+            first = self.output.resume_point(&chunks_updated, digest);
+            info!("Resuming at chunk {} of {}", first, chunks_updated.len());
+            for c in chunks_updated[..first].iter() {
+                self.progress.chunk_done(c.size, ChunkSource::Output);
+            }
+        }
+        let remaining = &chunks_updated[first..];
+        let zero_ids = zero_chunk_ids(&chunks_updated, self.new_index.chunk_sizes(), digest);
+        let mut cache = ChunkCache::new(reader::CACHE_CHUNKS_DEFAULT);
+        let mut sparse = 0;
         
         info!("Started assembling");
-        match &mut self.seed_index {
-            Some(seed_index) => {
-                info!("Found seed index");
-                seed_index.read();
-                let chunks_from_seed = seed_index.getChunkData();
-                match &mut self.seed {
-                    Some(seed) => {
-                        for uc in chunks_updated.iter() {
-                            let mut should_get_chunk = true;
-                            for c in chunks_from_seed.iter() {
-                                if c.id == uc.id {
-                                    //Read the chunk and assemble
-                                    info!("Getting chunk from seed");
-                                    let buf = seed.read_chunk(c.start, c.size);
-                                    self.output.write_all(buf);
-                                    should_get_chunk = false;
-                                    break;
+        if !self.worker_stores.is_empty() {
+            let stats = parallel::assemble_parallel(self, remaining, total_size, digest, &zero_ids);
+            skipped = stats.skipped;
+            sparse = stats.sparse;
+        } else {
+            match &mut self.seed_index {
+                Some(seed_index) => {
+                    info!("Found seed index");
+                    seed_index.read();
+                    let seed_chunks: HashMap<[u8;32], (u64, u64)> = seed_index.get_chunk_data().iter().map(|c| (c.id, (c.start, c.size))).collect();
+                    let (mut cloned, mut copied_range, mut copied) = (0, 0, 0);
+                    match &mut self.seed {
+                        Some(seed) => {
+                            for uc in remaining.iter() {
+                                if self.skip_matching && self.output.matches(uc.start, uc.size, &uc.id, digest) {
+                                    self.output.skip(uc.size);
+                                    self.progress.chunk_done(uc.size, ChunkSource::Output);
+                                    skipped += 1;
+                                    continue;
                                 }
+                                if zero_ids.contains(&uc.id) {
+                                    sparse += self.output.write_zeros(uc.size);
+                                    self.progress.chunk_done(uc.size, ChunkSource::Zero);
+                                    continue;
+                                }
+                                let from_seed = match seed_chunks.get(&uc.id) {
+                                    Some(seed_chunk) => verify::copy_from_seed(&seed.file, *seed_chunk, &self.output.file, uc, digest),
+                                    None => None
+                                };
+                                match from_seed {
+                                    Some(copy) => {
+                                        info!("Got chunk from seed");
+                                        match copy {
+                                            seed::SeedCopy::Cloned => cloned += uc.size,
+                                            seed::SeedCopy::CopiedRange => copied_range += uc.size,
+                                            seed::SeedCopy::Copied => copied += uc.size
+                                        }
+                                        self.progress.seed_copied(uc.size, copy);
+                                        self.progress.chunk_done(uc.size, ChunkSource::Seed);
+                                    },
+                                    None => {
+                                        // Get chunks from update
+                                        info!("Could not get chunk from the seed, Getting chunk from store");
+                                        let (chunk_bytes, source) = fetch_chunk(&mut self.store, &mut cache, uc, digest);
+                                        self.output.file.write_all_at(&chunk_bytes, uc.start).expect("Error: Cannot write chunk to output");
+                                        self.progress.chunk_done(uc.size, source);
+                                    }
+                                }
+                                self.output.skip(uc.size);
                             }
-                            if should_get_chunk {
-                                // Get chunks from update
-                                info!("Could not find chunk in the seed, Getting chunk from store");
-                                let chunk_bytes = self.store.read_item(uc.id.to_vec());
-                                self.output.write_all(chunk_bytes);
-                            }
+                        },
+                        None => {
+                            panic!("Seed file needed");
                         }
-                    },
-                    None => {
-                        panic!("Seed file needed");
+                    }
+                    info!("Seed bytes cloned: {}, copied in kernel: {}, copied: {}", cloned, copied_range, copied);
+                },
+                None => {
+                    for uc in remaining.iter() {
+                        if self.skip_matching && self.output.matches(uc.start, uc.size, &uc.id, digest) {
+                            self.output.skip(uc.size);
+                            self.progress.chunk_done(uc.size, ChunkSource::Output);
+                            skipped += 1;
+                            continue;
+                        }
+                        if zero_ids.contains(&uc.id) {
+                            sparse += self.output.write_zeros(uc.size);
+                            self.progress.chunk_done(uc.size, ChunkSource::Zero);
+                            continue;
+                        }
+                        // Should download chunks
+                        info!("Getting chunk from store");
+                        let (chunk_bytes, source) = fetch_chunk(&mut self.store, &mut cache, uc, digest);
+                        self.output.write_all(chunk_bytes);
+                        self.progress.chunk_done(uc.size, source);
                     }
                 }
+            }
+        }
+        self.progress.finish();
+        if self.skip_matching {
+            info!("Skipped {} of {} chunks already in place", skipped, chunks_updated.len());
+        }
+        if sparse > 0 {
+            info!("Left {} bytes of zero chunks sparse", sparse);
+        }
+        if (self.resume || sparse > 0) && self.output.device_size.is_none() {
+            // A stale output may have been longer than the one assembled, a trailing hole shorter
+            self.output.file.set_len(total_size).expect("Error: Cannot resize output");
+        }
+        if self.output.device_size.is_some() {
+            Rc::get_mut(&mut self.output.file).unwrap().sync_all().expect("Error: Cannot sync output device");
+        }
+    }
+}
+impl AssemblerConfig {
+    // Writes the chunks of the index in order to out, which is never sought or read back, so
+    // any writer will do. Seeds are used, the options needing a seekable output are not.
+    // Returns the number of bytes written.
+    pub fn assemble_to<W: Write>(&mut self, out: &mut W) -> u64 {
+        self.assemble_range(0, None, out)
+    }
+
+    // Like assemble_to, but only writes length bytes (or up to the end) starting at offset of
+    // the blob, only the chunks overlapping them are fetched
+    pub fn assemble_range<W: Write>(&mut self, offset: u64, length: Option<u64>, out: &mut W) -> u64 {
+        self.new_index.read();
+        let all_chunks = self.new_index.get_chunk_data();
+        let chunks = chunks_in_range(&all_chunks, offset, length);
+        let digest = self.new_index.digest();
+        let zero_ids = zero_chunk_ids(&all_chunks, self.new_index.chunk_sizes(), digest);
+        let mut cache = ChunkCache::new(reader::CACHE_CHUNKS_DEFAULT);
+        let seed_chunks: HashMap<[u8;32], (u64, u64)> = match (&self.seed, &mut self.seed_index) {
+            (Some(_), Some(seed_index)) => {
+                seed_index.read();
+                seed_index.get_chunk_data().iter().map(|c| (c.id, (c.start, c.size))).collect()
             },
-            None => {
-                for uc in chunks_updated.iter() {
-                    // Should download chunks
-                    info!("Getting chunk from store");
-                    let chunk_bytes = self.store.read_item(uc.id.to_vec());
-                    self.output.write_all(chunk_bytes);
+            _ => HashMap::new()
+        };
+        let blob_size = all_chunks.last().map_or(0, |c| c.start + c.size);
+        if offset > blob_size {
+            panic!("Range starts at {}, past the end of the {} byte blob", offset, blob_size);
+        }
+        let end = length.map_or(blob_size, |l| std::cmp::min(blob_size, offset.saturating_add(l)));
+
+        info!("Started assembling bytes {} to {} in order", offset, end);
+        self.progress.start(end - offset);
+        for uc in chunks.iter() {
+            let (chunk_bytes, source) = if zero_ids.contains(&uc.id) {
+                (vec![0; uc.size as usize], ChunkSource::Zero)
+            } else {
+                // The seed may have changed since it was indexed, only chunks matching their id are used
+                let from_seed = match (&mut self.seed, seed_chunks.get(&uc.id)) {
+                    (Some(seed), Some((start, size))) => Some(seed.read_chunk(*start, *size))
+                        .filter(|bytes| bytes.len() as u64 == uc.size && digest.sum(bytes) == uc.id),
+                    _ => None
+                };
+                match from_seed {
+                    Some(chunk_bytes) => (chunk_bytes, ChunkSource::Seed),
+                    None => fetch_chunk(&mut self.store, &mut cache, uc, digest)
                 }
+            };
+            // The first and last chunk may stick out of the range
+            let from = (std::cmp::max(offset, uc.start) - uc.start) as usize;
+            let to = (std::cmp::min(end, uc.start + uc.size) - uc.start) as usize;
+            if let Err(e) = out.write_all(&chunk_bytes[from..to]) {
+                panic!("Could not write output, {:?}", e);
             }
+            if source == ChunkSource::Seed {
+                self.progress.seed_copied((to - from) as u64, seed::SeedCopy::Copied);
+            }
+            self.progress.chunk_done((to - from) as u64, source);
         }
+        out.flush().expect("Error: Cannot flush output");
+        self.progress.finish();
+        end - offset
+    }
+}
+
+// Chunks holding any of the length bytes (or all up to the end) from offset, chunks are in order
+pub fn chunks_in_range(chunks: &[index::ChunkData], offset: u64, length: Option<u64>) -> &[index::ChunkData] {
+    let first = chunks.partition_point(|c| c.start + c.size <= offset);
+    let last = match length {
+        Some(l) => chunks.partition_point(|c| c.start < offset.saturating_add(l)),
+        None => chunks.len()
+    };
+    &chunks[first..std::cmp::max(first, last)]
+}
+
+// A chunk from the cache when it was fetched recently, the index may repeat it, from the store
+// otherwise, verified against its id
+fn fetch_chunk(store: &mut Box<dyn store::Store>, cache: &mut ChunkCache, c: &index::ChunkData, digest: ChunkDigest) -> (Vec<u8>, ChunkSource) {
+    if let Some(chunk_bytes) = cache.get(&c.id) {
+        return (chunk_bytes.clone(), ChunkSource::Cache);
     }
-}
\ No newline at end of file
+    let chunk_bytes = verify::read_from_store(store, c, digest);
+    cache.insert(c.id, chunk_bytes.clone());
+    (chunk_bytes, ChunkSource::Store)
+}
+
+// Ids of chunks holding nothing but zeros: those the chunker cuts a run of zeros into, casync
+// cutting right at the minimum size, and the last chunk of the blob, which the end of the input
+// can cut to any size
+fn zero_chunk_ids(chunks: &[index::ChunkData], chunk_sizes: (u64, u64, u64), digest: ChunkDigest) -> HashSet<[u8;32]> {
+    let (min_size, avg_size, max_size) = chunk_sizes;
+    let mut sizes: HashSet<u64> = chunker::zero_chunk_sizes(min_size, max_size, avg_size).into_iter().collect();
+    sizes.insert(min_size);
+    if let Some(last) = chunks.last() {
+        sizes.insert(last.size);
+    }
+    sizes.iter()
+        .filter(|size| **size > 0)
+        .map(|size| digest.sum(&vec![0; *size as usize]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use crate::chunker::tests::random_bytes;
+    use crate::index::Index;
+    use crate::progress::{Counter, NoProgress, SourceCounts};
+
+    const MIN: u64 = 4 * 1024;
+    const AVG: u64 = 16 * 1024;
+    const MAX: u64 = 64 * 1024;
+
+    fn new_store(dir: &Path) -> Box<dyn store::Store> {
+        let mut store = store::LocalStore::new(dir.join("store").to_str().unwrap(), MIN, MAX, AVG);
+        store.compression = store::ChunkCompression::Zstd(1);
+        Box::new(store)
+    }
+
+    // Chunks data into the store of dir, returns the path of its index
+    fn make_index(dir: &Path, data: &[u8]) -> PathBuf {
+        let input = dir.join("input");
+        std::fs::write(&input, data).unwrap();
+        let index_path = dir.join("input.caibx");
+        let mut config = chunker::ChunkerConfig {
+            index: Box::new(index::LocalIndexFile::new(index_path.to_str().unwrap())),
+            store: new_store(dir),
+            worker_stores: Vec::new(),
+            source: Box::new(local_io::LocalSourceFile::new(String::from(input.to_str().unwrap()))),
+            min_size: MIN,
+            max_size: MAX,
+            avg_size: AVG,
+            digest: ChunkDigest::default(),
+            progress: Box::new(NoProgress)
+        };
+        config.chunk();
+        index_path
+    }
+
+    fn extract(dir: &Path, index_path: &Path, output: Box<local_io::LocalOutputFile>, workers: usize, resume: bool) -> Arc<Mutex<SourceCounts>> {
+        let (counter, counts) = Counter::new(Box::new(NoProgress));
+        let mut config = AssemblerConfig {
+            seed: None,
+            seed_index: None,
+            store: new_store(dir),
+            worker_stores: (0..workers).map(|_| new_store(dir)).collect(),
+            new_index: Box::new(index::LocalIndexFile::open(index_path.to_str().unwrap())),
+            output,
+            skip_matching: false,
+            in_place: false,
+            resume,
+            progress: Box::new(counter)
+        };
+        config.assemble();
+        counts
+    }
+
+    // Bytes of the chunks in the index of data which hold nothing but zeros
+    fn zero_chunk_bytes(index_path: &Path, data: &[u8]) -> u64 {
+        let mut index = index::LocalIndexFile::open(index_path.to_str().unwrap());
+        index.read();
+        index.get_chunk_data().iter()
+            .filter(|c| data[c.start as usize..(c.start + c.size) as usize].iter().all(|b| *b == 0))
+            .map(|c| c.size)
+            .sum()
+    }
+
+    #[test]
+    fn zero_chunks_of_an_image_are_left_sparse() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data = random_bytes(200_000);
+        data.extend(vec![0; 5 * MAX as usize]);
+        data.extend(random_bytes(100_000));
+        // Ends in zeros, the last chunk is cut by the end of the input
+        data.extend(vec![0; MAX as usize + 12_345]);
+        let index_path = make_index(dir.path(), &data);
+        let zero_bytes = zero_chunk_bytes(&index_path, &data);
+        assert!(zero_bytes > 4 * MAX, "only {} bytes in zero chunks", zero_bytes);
+
+        let output_path = dir.path().join("output");
+        let counts = extract(dir.path(), &index_path, Box::new(local_io::LocalOutputFile::new(output_path.to_str().unwrap())), 0, false);
+        assert_eq!(std::fs::read(&output_path).unwrap(), data);
+        assert_eq!(counts.lock().unwrap().zero_bytes, zero_bytes);
+    }
+
+    #[test]
+    fn resuming_a_complete_output_with_workers_keeps_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data = random_bytes(300_000);
+        data.extend(vec![0; 3 * MAX as usize]);
+        let index_path = make_index(dir.path(), &data);
+        let output_path = dir.path().join("output");
+        let output = output_path.to_str().unwrap();
+        extract(dir.path(), &index_path, Box::new(local_io::LocalOutputFile::new(output)), 0, false);
+
+        let counts = extract(dir.path(), &index_path, Box::new(local_io::LocalOutputFile::open_in_place(output)), 3, true);
+        assert_eq!(std::fs::read(&output_path).unwrap(), data);
+        let counts = counts.lock().unwrap();
+        assert_eq!(counts.output_bytes, data.len() as u64);
+        assert_eq!(counts.store_bytes, 0);
+    }
+
+    #[test]
+    fn workers_resume_with_seed_and_zero_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data = random_bytes(300_000);
+        data.extend(vec![0; 3 * MAX as usize]);
+        data.extend(random_bytes(200_000).iter().map(|b| b ^ 0x5a));
+        let index_path = make_index(dir.path(), &data);
+        // The seed holds the blob as well, the stale output only its start followed by junk
+        let seed_path = dir.path().join("input");
+        let output_path = dir.path().join("output");
+        let mut stale = data[..100_000].to_vec();
+        stale.resize(data.len(), 0xff);
+        std::fs::write(&output_path, &stale).unwrap();
+
+        let (counter, counts) = Counter::new(Box::new(NoProgress));
+        let mut config = AssemblerConfig {
+            seed: Some(seed::LocalSeedFile::new(seed_path.to_str().unwrap())),
+            seed_index: Some(Box::new(index::LocalIndexFile::open(index_path.to_str().unwrap()))),
+            store: new_store(dir.path()),
+            worker_stores: (0..3).map(|_| new_store(dir.path())).collect(),
+            new_index: Box::new(index::LocalIndexFile::open(index_path.to_str().unwrap())),
+            output: Box::new(local_io::LocalOutputFile::open_in_place(output_path.to_str().unwrap())),
+            skip_matching: true,
+            in_place: false,
+            resume: true,
+            progress: Box::new(counter)
+        };
+        config.assemble();
+        drop(config);
+
+        assert_eq!(std::fs::read(&output_path).unwrap(), data);
+        let counts = counts.lock().unwrap();
+        assert!(counts.output_bytes > 0);
+        assert!(counts.zero_bytes > 0);
+        assert!(counts.seed_bytes > 0);
+        assert_eq!(counts.store_bytes, 0);
+        assert_eq!(counts.output_bytes + counts.zero_bytes + counts.seed_bytes, data.len() as u64);
+    }
+
+    #[test]
+    fn repeated_chunks_come_from_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let part = random_bytes(100_000);
+        let mut data = part.clone();
+        data.extend(&part);
+        data.extend(&part);
+        let index_path = make_index(dir.path(), &data);
+        let output_path = dir.path().join("output");
+        let counts = extract(dir.path(), &index_path, Box::new(local_io::LocalOutputFile::new(output_path.to_str().unwrap())), 0, false);
+        assert_eq!(std::fs::read(&output_path).unwrap(), data);
+        let counts = counts.lock().unwrap();
+        assert!(counts.cache_bytes > 100_000, "{} bytes from the cache", counts.cache_bytes);
+        assert_eq!(counts.store_bytes + counts.cache_bytes, data.len() as u64);
+    }
+
+    #[test]
+    fn changed_seed_chunks_come_from_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = random_bytes(400_000);
+        let index_path = make_index(dir.path(), &data);
+        // The seed changed in the middle and got truncated after it was indexed
+        let seed_path = dir.path().join("input");
+        let mut changed = data[..300_000].to_vec();
+        changed[150_000] ^= 0xff;
+        std::fs::write(&seed_path, &changed).unwrap();
+
+        let new_config = |output: &Path, workers: usize| {
+            let (counter, counts) = Counter::new(Box::new(NoProgress));
+            let config = AssemblerConfig {
+                seed: Some(seed::LocalSeedFile::new(seed_path.to_str().unwrap())),
+                seed_index: Some(Box::new(index::LocalIndexFile::open(index_path.to_str().unwrap()))),
+                store: new_store(dir.path()),
+                worker_stores: (0..workers).map(|_| new_store(dir.path())).collect(),
+                new_index: Box::new(index::LocalIndexFile::open(index_path.to_str().unwrap())),
+                output: Box::new(local_io::LocalOutputFile::new(output.to_str().unwrap())),
+                skip_matching: false,
+                in_place: false,
+                resume: false,
+                progress: Box::new(counter)
+            };
+            (config, counts)
+        };
+        for workers in [0, 3].iter() {
+            let output_path = dir.path().join(format!("output-{}", workers));
+            let (mut config, counts) = new_config(&output_path, *workers);
+            config.assemble();
+            drop(config);
+            assert_eq!(std::fs::read(&output_path).unwrap(), data, "{} workers", workers);
+            let counts = counts.lock().unwrap();
+            assert!(counts.seed_bytes > 0);
+            assert!(counts.store_bytes > 100_000, "{} bytes from the store", counts.store_bytes);
+        }
+
+        let (mut config, counts) = new_config(&dir.path().join("unused"), 0);
+        let mut out = Vec::new();
+        config.assemble_to(&mut out);
+        assert_eq!(out, data);
+        assert!(counts.lock().unwrap().store_bytes > 100_000);
+    }
+
+    #[test]
+    fn seed_bytes_are_counted_by_how_they_were_copied() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = random_bytes(300_000);
+        let index_path = make_index(dir.path(), &data);
+        let seed_path = dir.path().join("input");
+        for workers in [0, 3].iter() {
+            let output_path = dir.path().join(format!("output-{}", workers));
+            let (counter, counts) = Counter::new(Box::new(NoProgress));
+            let mut config = AssemblerConfig {
+                seed: Some(seed::LocalSeedFile::new(seed_path.to_str().unwrap())),
+                seed_index: Some(Box::new(index::LocalIndexFile::open(index_path.to_str().unwrap()))),
+                store: new_store(dir.path()),
+                worker_stores: (0..*workers).map(|_| new_store(dir.path())).collect(),
+                new_index: Box::new(index::LocalIndexFile::open(index_path.to_str().unwrap())),
+                output: Box::new(local_io::LocalOutputFile::new(output_path.to_str().unwrap())),
+                skip_matching: false,
+                in_place: false,
+                resume: false,
+                progress: Box::new(counter)
+            };
+            config.assemble();
+            drop(config);
+            assert_eq!(std::fs::read(&output_path).unwrap(), data);
+            let counts = counts.lock().unwrap();
+            // Seed and output share the temporary directory
+            assert!(counts.cloned_bytes + counts.copied_range_bytes > 0, "{} workers", workers);
+            assert_eq!(counts.cloned_bytes + counts.copied_range_bytes + counts.copied_bytes, data.len() as u64);
+            assert_eq!(counts.seed_bytes, data.len() as u64);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use log::info;
+
+use crate::digest::ChunkDigest;
+use crate::index::ChunkData;
+use crate::progress::ChunkSource;
+use crate::io;
+use crate::seed::SeedCopy;
+use super::AssemblerConfig;
+use super::verify::{copy_from_seed, holds_chunk, read_from_store};
+
+// Chunks already counted by the caller
+#[derive(Default)]
+pub struct ParallelStats {
+    pub skipped: u64,
+    pub sparse: u64,
+    // Seed bytes by how they got into the output
+    cloned: u64,
+    copied_range: u64,
+    copied: u64
+}
+
+// Assembles chunks with one worker per store. Each worker takes the next chunk, leaves it alone
+// when the output already holds it and skip_matching is set, and otherwise leaves it sparse if
+// it is all zeros, copies it from the seed or fetches it from its store and verifies it against
+// its id. Chunks are written at their offsets in any order.
+pub fn assemble_parallel(config: &mut AssemblerConfig, chunks: &[ChunkData], total_size: u64, digest: ChunkDigest, zero_ids: &HashSet<[u8;32]>) -> ParallelStats {
+    if chunks.is_empty() {
+        return ParallelStats::default();
+    }
+    let is_device = config.output.device_size.is_some();
+    if !is_device {
+        // Workers write past the end, the file gets its final size first
+        config.output.file.set_len(total_size).expect("Error: Cannot resize output");
+    }
+
+    let seed_chunks: HashMap<[u8;32], (u64, u64)> = match &mut config.seed_index {
+        Some(seed_index) => {
+            seed_index.read();
+            seed_index.get_chunk_data().iter().map(|c| (c.id, (c.start, c.size))).collect()
+        },
+        None => HashMap::new()
+    };
+    let seed: Option<&File> = config.seed.as_ref().map(|s| &*s.file);
+    let file: &File = &config.output.file;
+    let skip_matching = config.skip_matching;
+    let progress = Mutex::new(&mut config.progress);
+    let mut stores = vec![&mut config.store];
+    stores.extend(config.worker_stores.iter_mut());
+    info!("Assembling {} chunks with {} workers", chunks.len(), stores.len());
+
+    let (chunk_tx, chunk_rx) = mpsc::channel::<&ChunkData>();
+    for uc in chunks.iter() {
+        chunk_tx.send(uc).unwrap();
+    }
+    drop(chunk_tx);
+    let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+
+    let stats = thread::scope(|scope| {
+        let workers: Vec<_> = stores.into_iter().map(|store| {
+            let chunk_rx = chunk_rx.clone();
+            let progress = &progress;
+            let seed_chunks = &seed_chunks;
+            scope.spawn(move || {
+                let mut stats = ParallelStats::default();
+                loop {
+                    let next = chunk_rx.lock().unwrap().recv();
+                    let uc = match next {
+                        Ok(uc) => uc,
+                        Err(_) => break
+                    };
+                    if skip_matching && holds_chunk(file, uc, digest) {
+                        progress.lock().unwrap().chunk_done(uc.size, ChunkSource::Output);
+                        stats.skipped += 1;
+                        continue;
+                    }
+                    if zero_ids.contains(&uc.id) {
+                        stats.sparse += io::write_zeros_at(file, uc.start, uc.size, is_device);
+                        progress.lock().unwrap().chunk_done(uc.size, ChunkSource::Zero);
+                        continue;
+                    }
+                    if let (Some(seed), Some(seed_chunk)) = (seed, seed_chunks.get(&uc.id)) {
+                        if let Some(copy) = copy_from_seed(seed, *seed_chunk, file, uc, digest) {
+                            match copy {
+                                SeedCopy::Cloned => stats.cloned += uc.size,
+                                SeedCopy::CopiedRange => stats.copied_range += uc.size,
+                                SeedCopy::Copied => stats.copied += uc.size
+                            }
+                            let mut progress = progress.lock().unwrap();
+                            progress.seed_copied(uc.size, copy);
+                            progress.chunk_done(uc.size, ChunkSource::Seed);
+                            continue;
+                        }
+                    }
+                    let chunk_bytes = read_from_store(store, uc, digest);
+                    file.write_all_at(&chunk_bytes, uc.start).expect("Error: Cannot write chunk to output");
+                    progress.lock().unwrap().chunk_done(uc.size, ChunkSource::Store);
+                }
+                stats
+            })
+        }).collect();
+        workers.into_iter().map(|w| w.join().expect("Error: Assembly worker failed")).fold(ParallelStats::default(), |total, s| ParallelStats {
+            skipped: total.skipped + s.skipped,
+            sparse: total.sparse + s.sparse,
+            cloned: total.cloned + s.cloned,
+            copied_range: total.copied_range + s.copied_range,
+            copied: total.copied + s.copied
+        })
+    });
+    if seed.is_some() {
+        info!("Seed bytes cloned: {}, copied in kernel: {}, copied: {}", stats.cloned, stats.copied_range, stats.copied);
+    }
+    stats
+}
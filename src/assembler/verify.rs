@@ -0,0 +1,64 @@
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::thread;
+use log::{info, warn};
+
+use crate::digest::ChunkDigest;
+use crate::index::ChunkData;
+use crate::seed::{self, SeedCopy};
+use crate::store;
+use crate::utils;
+
+// Checks every chunk of an index against the output, split over workers reading their share
+// of the file at once. Returns the chunks which don't match, in order.
+pub fn find_bad_chunks(file: &File, chunks: &[ChunkData], digest: ChunkDigest, workers: usize) -> Vec<ChunkData> {
+    let per_worker = std::cmp::max(1, (chunks.len() + workers - 1) / std::cmp::max(1, workers));
+    info!("Verifying {} chunks with {} workers", chunks.len(), workers);
+    thread::scope(|scope| {
+        let handles: Vec<_> = chunks.chunks(per_worker).map(|part| {
+            scope.spawn(move || part.iter().filter(|c| !holds_chunk(file, c, digest)).cloned().collect::<Vec<ChunkData>>())
+        }).collect();
+        handles.into_iter().flat_map(|h| h.join().expect("Error: Verify worker failed")).collect()
+    })
+}
+
+// Writes bad chunks again from the store
+pub fn repair(file: &File, bad: &[ChunkData], digest: ChunkDigest, store: &mut Box<dyn store::Store>) {
+    for c in bad.iter() {
+        let chunk_bytes = read_from_store(store, c, digest);
+        file.write_all_at(&chunk_bytes, c.start).expect("Error: Cannot write chunk to output");
+    }
+    info!("Repaired {} chunks", bad.len());
+}
+
+// Reads a chunk from the store, a chunk not matching its id is never written to an output
+pub fn read_from_store(store: &mut Box<dyn store::Store>, c: &ChunkData, digest: ChunkDigest) -> Vec<u8> {
+    let chunk_bytes = store.read_item(c.id.to_vec());
+    if chunk_bytes.len() as u64 != c.size || digest.sum(&chunk_bytes) != c.id {
+        panic!("Chunk {} from store does not match its id", utils::bytes_to_hex(c.id.to_vec()));
+    }
+    chunk_bytes
+}
+
+// Copies a chunk from the seed to its offset in the output and checks what was written, the
+// seed may have changed since it was indexed. Returns how the chunk was copied, None when the
+// output does not hold it and it has to come from the store.
+pub fn copy_from_seed(seed: &File, seed_chunk: (u64, u64), file: &File, c: &ChunkData, digest: ChunkDigest) -> Option<SeedCopy> {
+    let (start, size) = seed_chunk;
+    let copy = seed::copy_range(seed, start, size, file, c.start)?;
+    if holds_chunk(file, c, digest) {
+        Some(copy)
+    } else {
+        warn!("Chunk at {} of the seed changed since it was indexed", start);
+        None
+    }
+}
+
+// Whether the file holds the chunk at its offset
+pub fn holds_chunk(file: &File, c: &ChunkData, digest: ChunkDigest) -> bool {
+    let mut buf = vec![0; c.size as usize];
+    match file.read_exact_at(&mut buf, c.start) {
+        Ok(()) => digest.sum(&buf) == c.id,
+        Err(_) => false
+    }
+}
@@ -0,0 +1,262 @@
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::PermissionsExt;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use byteorder::{ByteOrder, LittleEndian};
+use log::{debug, warn};
+
+use super::*;
+
+// Restores the tree serialized in a catar stream below dest, returns the number of entries
+pub fn decode_tree<R: Read>(input: R, dest: &Path) -> u64 {
+    let mut decoder = Decoder {
+        input,
+        entries: 0,
+        // Owners can only be restored by root, others get their own files
+        restore_owner: unsafe { libc::geteuid() } == 0
+    };
+    decoder.decode_entry(dest, true);
+    if decoder.next_header().is_some() {
+        panic!("Unexpected data after the end of the archive");
+    }
+    decoder.entries
+}
+
+struct Decoder<R: Read> {
+    input: R,
+    entries: u64,
+    restore_owner: bool
+}
+
+struct Entry {
+    mode: u64,
+    uid: u64,
+    gid: u64,
+    mtime: u64
+}
+
+impl<R: Read> Decoder<R> {
+    fn read_exact(&mut self, buf: &mut [u8]) {
+        if let Err(e) = self.input.read_exact(buf) {
+            panic!("Archive is truncated or unreadable, {:?}", e);
+        }
+    }
+
+    fn read_u64s(&mut self, count: usize) -> Vec<u64> {
+        let mut buf = vec![0; count * 8];
+        self.read_exact(&mut buf);
+        let mut values = vec![0; count];
+        LittleEndian::read_u64_into(&buf, &mut values);
+        values
+    }
+
+    // Size and type of the next item, None at the end of the stream
+    fn next_header(&mut self) -> Option<(u64, u64)> {
+        let mut buf = [0; HEADER_SIZE as usize];
+        let mut read = 0;
+        while read < buf.len() {
+            match self.input.read(&mut buf[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => {
+                    panic!("Could not read archive, {:?}", e);
+                }
+            }
+        }
+        if read == 0 {
+            return None;
+        }
+        if read < buf.len() {
+            panic!("Archive is truncated");
+        }
+        let size = LittleEndian::read_u64(&buf[..8]);
+        if size < HEADER_SIZE {
+            panic!("Invalid item size {} in archive", size);
+        }
+        Some((size, LittleEndian::read_u64(&buf[8..])))
+    }
+
+    fn expect_header(&mut self) -> (u64, u64) {
+        match self.next_header() {
+            Some(header) => header,
+            None => {
+                panic!("Archive ends in the middle of an entry");
+            }
+        }
+    }
+
+    fn skip(&mut self, size: u64) {
+        let skipped = io::copy(&mut (&mut self.input).take(size), &mut io::sink()).expect("Error: Cannot read archive");
+        if skipped != size {
+            panic!("Archive is truncated");
+        }
+    }
+
+    // Payload of a NUL terminated string item
+    fn read_string(&mut self, size: u64) -> Vec<u8> {
+        let mut buf = vec![0; (size - HEADER_SIZE) as usize];
+        self.read_exact(&mut buf);
+        if buf.pop() != Some(0) {
+            panic!("Unterminated string in archive");
+        }
+        buf
+    }
+
+    // Skips metadata items this decoder does not restore (user and group names, xattrs,
+    // ACLs, ...) and returns the header of the next item which is not one of them
+    fn next_content_header(&mut self) -> Option<(u64, u64)> {
+        loop {
+            let (size, item_type) = self.next_header()?;
+            match item_type {
+                CA_FORMAT_ENTRY | CA_FORMAT_FILENAME | CA_FORMAT_GOODBYE | CA_FORMAT_PAYLOAD | CA_FORMAT_SYMLINK | CA_FORMAT_DEVICE => {
+                    return Some((size, item_type));
+                },
+                CA_FORMAT_USER | CA_FORMAT_GROUP => {
+                    debug!("Skipping user or group name, ids are restored instead");
+                    self.skip(size - HEADER_SIZE);
+                },
+                _ => {
+                    debug!("Skipping archive item {:x}", item_type);
+                    self.skip(size - HEADER_SIZE);
+                }
+            }
+        }
+    }
+
+    fn decode_entry(&mut self, path: &Path, is_root: bool) {
+        let (size, item_type) = self.expect_header();
+        if item_type != CA_FORMAT_ENTRY || size != ENTRY_SIZE {
+            panic!("Expected an entry for {:?} in archive", path);
+        }
+        let values = self.read_u64s(6);
+        let entry = Entry { mode: values[1], uid: values[3], gid: values[4], mtime: values[5] };
+        self.entries += 1;
+
+        match entry.mode as u32 & libc::S_IFMT {
+            libc::S_IFDIR => {
+                if let Err(e) = fs::create_dir(path) {
+                    if !(is_root && e.kind() == io::ErrorKind::AlreadyExists && path.is_dir()) {
+                        panic!("Could not create directory {:?}, {:?}", path, e);
+                    }
+                }
+                self.decode_children(path);
+            },
+            libc::S_IFREG => {
+                let (size, _) = self.expect_content(CA_FORMAT_PAYLOAD, path);
+                // Never write through a symlink left at the destination
+                remove_existing(path);
+                let mut file = match OpenOptions::new().write(true).create(true).truncate(true).open(path) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        panic!("Could not create {:?}, {:?}", path, e);
+                    }
+                };
+                let copied = io::copy(&mut (&mut self.input).take(size - HEADER_SIZE), &mut file).expect("Error: Cannot write file");
+                if copied != size - HEADER_SIZE {
+                    panic!("Archive is truncated");
+                }
+            },
+            libc::S_IFLNK => {
+                let (size, _) = self.expect_content(CA_FORMAT_SYMLINK, path);
+                let target = self.read_string(size);
+                remove_existing(path);
+                if let Err(e) = std::os::unix::fs::symlink(OsString::from_vec(target), path) {
+                    panic!("Could not create symlink {:?}, {:?}", path, e);
+                }
+            },
+            libc::S_IFBLK | libc::S_IFCHR => {
+                self.expect_content(CA_FORMAT_DEVICE, path);
+                let device = self.read_u64s(2);
+                let (major, minor) = (device[0], device[1]);
+                let dev = ((major & 0xfffff000) << 32) | ((major & 0xfff) << 8) | ((minor & 0xffffff00) << 12) | (minor & 0xff);
+                make_node(path, entry.mode, dev);
+            },
+            libc::S_IFIFO | libc::S_IFSOCK => {
+                make_node(path, entry.mode, 0);
+            },
+            _ => {
+                panic!("Unknown file type {:o} for {:?} in archive", entry.mode, path);
+            }
+        }
+        self.restore_metadata(path, &entry);
+    }
+
+    fn expect_content(&mut self, expected: u64, path: &Path) -> (u64, u64) {
+        match self.next_content_header() {
+            Some((size, item_type)) if item_type == expected => (size, item_type),
+            _ => {
+                panic!("Archive is missing the content of {:?}", path);
+            }
+        }
+    }
+
+    fn decode_children(&mut self, path: &Path) {
+        loop {
+            match self.next_content_header() {
+                Some((size, CA_FORMAT_FILENAME)) => {
+                    let name = self.read_string(size);
+                    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+                        panic!("Invalid file name {:?} in archive", String::from_utf8_lossy(&name));
+                    }
+                    let child: PathBuf = path.join(std::ffi::OsStr::from_bytes(&name));
+                    debug!("Restoring {:?}", child);
+                    self.decode_entry(&child, false);
+                },
+                Some((size, CA_FORMAT_GOODBYE)) => {
+                    // Only needed for lookups, the children were all read in order
+                    self.skip(size - HEADER_SIZE);
+                    return;
+                },
+                _ => {
+                    panic!("Archive is missing the end of directory {:?}", path);
+                }
+            }
+        }
+    }
+
+    fn restore_metadata(&self, path: &Path, entry: &Entry) {
+        let is_symlink = entry.mode as u32 & libc::S_IFMT == libc::S_IFLNK;
+        if self.restore_owner {
+            if let Err(e) = std::os::unix::fs::lchown(path, Some(entry.uid as u32), Some(entry.gid as u32)) {
+                warn!("Could not restore owner of {:?}, {:?}", path, e);
+            }
+        }
+        // Symlinks have no permissions of their own on Linux
+        if !is_symlink {
+            if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(entry.mode as u32 & 0o7777)) {
+                panic!("Could not restore permissions of {:?}, {:?}", path, e);
+            }
+        }
+        let times = [
+            libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+            libc::timespec { tv_sec: (entry.mtime / 1_000_000_000) as libc::time_t, tv_nsec: (entry.mtime % 1_000_000_000) as libc::c_long }
+        ];
+        let c_path = c_path(path);
+        if unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+            warn!("Could not restore modification time of {:?}, {:?}", path, io::Error::last_os_error());
+        }
+    }
+}
+
+fn c_path(path: &Path) -> CString {
+    CString::new(path.as_os_str().as_bytes()).expect("Error: Path contains a NUL byte")
+}
+
+fn remove_existing(path: &Path) {
+    if fs::symlink_metadata(path).is_ok() {
+        fs::remove_file(path).expect("Error: Cannot replace existing file");
+    }
+}
+
+// Device nodes, FIFOs and sockets
+fn make_node(path: &Path, mode: u64, dev: u64) {
+    remove_existing(path);
+    let c_path = c_path(path);
+    if unsafe { libc::mknod(c_path.as_ptr(), mode as libc::mode_t, dev as libc::dev_t) } != 0 {
+        panic!("Could not create {:?}, {:?}", path, io::Error::last_os_error());
+    }
+}
@@ -0,0 +1,148 @@
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use byteorder::{ByteOrder, LittleEndian};
+use log::debug;
+
+use super::*;
+
+// Writes the tree below root as catar to out, returns the size of the archive
+pub fn encode_tree<W: Write>(root: &Path, out: W, feature_flags: u64) -> u64 {
+    let metadata = match fs::symlink_metadata(root) {
+        Ok(m) => m,
+        Err(e) => {
+            panic!("Could not read {:?}, {:?}", root, e);
+        }
+    };
+    if !metadata.is_dir() {
+        panic!("{:?} is not a directory", root);
+    }
+    let mut encoder = Encoder { out, pos: 0, feature_flags };
+    encoder.encode_entry(root, &metadata);
+    encoder.out.flush().expect("Error: Cannot write archive");
+    encoder.pos
+}
+
+struct Encoder<W: Write> {
+    out: W,
+    // Bytes written so far, goodbye tables refer back to earlier items
+    pos: u64,
+    feature_flags: u64
+}
+
+impl<W: Write> Encoder<W> {
+    fn write(&mut self, buf: &[u8]) {
+        self.out.write_all(buf).expect("Error: Cannot write archive");
+        self.pos += buf.len() as u64;
+    }
+
+    fn header(&mut self, size: u64, item_type: u64) {
+        write_header(&mut self.out, size, item_type);
+        self.pos += HEADER_SIZE;
+    }
+
+    fn u64s(&mut self, values: &[u64]) {
+        let mut buf = vec![0; values.len() * 8];
+        LittleEndian::write_u64_into(values, &mut buf);
+        self.write(&buf);
+    }
+
+    // NUL terminated string item
+    fn string(&mut self, item_type: u64, value: &[u8]) {
+        self.header(HEADER_SIZE + value.len() as u64 + 1, item_type);
+        self.write(value);
+        self.write(&[0]);
+    }
+
+    fn encode_entry(&mut self, path: &Path, metadata: &Metadata) {
+        let entry_offset = self.pos;
+        let mtime = metadata.mtime() as u64 * 1_000_000_000 + metadata.mtime_nsec() as u64;
+        self.header(ENTRY_SIZE, CA_FORMAT_ENTRY);
+        let flags = self.feature_flags;
+        self.u64s(&[flags, metadata.mode() as u64, 0, metadata.uid() as u64, metadata.gid() as u64, mtime]);
+
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            self.encode_children(path, entry_offset);
+        } else if file_type.is_file() {
+            self.encode_payload(path, metadata.len());
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(path).expect("Error: Cannot read symlink");
+            self.string(CA_FORMAT_SYMLINK, target.as_os_str().as_bytes());
+        } else if file_type.is_block_device() || file_type.is_char_device() {
+            let rdev = metadata.rdev();
+            let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+            let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+            self.header(HEADER_SIZE + 16, CA_FORMAT_DEVICE);
+            self.u64s(&[major, minor]);
+        }
+        // FIFOs and sockets are fully described by their entry
+    }
+
+    fn encode_payload(&mut self, path: &Path, size: u64) {
+        let file = match File::open(path) {
+            Ok(f) => f,
+            Err(e) => {
+                panic!("Could not open {:?}, {:?}", path, e);
+            }
+        };
+        self.header(HEADER_SIZE + size, CA_FORMAT_PAYLOAD);
+        let copied = io::copy(&mut file.take(size), &mut self.out).expect("Error: Cannot archive file");
+        if copied != size {
+            panic!("{:?} changed while archiving it", path);
+        }
+        self.pos += copied;
+    }
+
+    fn encode_children(&mut self, path: &Path, entry_offset: u64) {
+        let mut children: Vec<fs::DirEntry> = match fs::read_dir(path) {
+            Ok(d) => d.filter_map(|e| e.ok()).collect(),
+            Err(e) => {
+                panic!("Could not list {:?}, {:?}", path, e);
+            }
+        };
+        // casync orders children by strcmp of their names
+        children.sort_by(|a, b| a.file_name().as_bytes().cmp(b.file_name().as_bytes()));
+
+        let mut items = Vec::new();
+        for child in children.iter() {
+            let name = child.file_name();
+            let filename_offset = self.pos;
+            self.string(CA_FORMAT_FILENAME, name.as_bytes());
+            let child_path = child.path();
+            let metadata = match fs::symlink_metadata(&child_path) {
+                Ok(m) => m,
+                Err(e) => {
+                    panic!("Could not read {:?}, {:?}", child_path, e);
+                }
+            };
+            debug!("Archiving {:?}", child_path);
+            self.encode_entry(&child_path, &metadata);
+            items.push(GoodbyeItem {
+                offset: filename_offset,
+                size: self.pos - filename_offset,
+                hash: filename_hash(name.as_bytes())
+            });
+        }
+        self.encode_goodbye(items, entry_offset);
+    }
+
+    fn encode_goodbye(&mut self, mut items: Vec<GoodbyeItem>, entry_offset: u64) {
+        let goodbye_offset = self.pos;
+        let size = HEADER_SIZE + (items.len() as u64 + 1) * GOODBYE_ITEM_SIZE;
+        for item in items.iter_mut() {
+            item.offset = goodbye_offset - item.offset;
+        }
+        items.sort_by_key(|item| item.hash);
+        let mut table = vec![GoodbyeItem { offset: 0, size: 0, hash: 0 }; items.len()];
+        make_bst(&items, &mut table, 0);
+
+        self.header(size, CA_FORMAT_GOODBYE);
+        for item in table.iter() {
+            self.u64s(&[item.offset, item.size, item.hash]);
+        }
+        self.u64s(&[goodbye_offset - entry_offset, size, CA_FORMAT_GOODBYE_TAIL_MARKER]);
+    }
+}
@@ -0,0 +1,165 @@
+// casync's catar archive format, a directory tree serialized depth first into a single stream.
+// Every item starts with a little endian u64 size (including the header) and u64 type.
+// A directory is its ENTRY, then FILENAME and the serialized child for every child in name
+// order, then a GOODBYE table which allows looking children up by name hash.
+use std::io::Write;
+use byteorder::{ByteOrder, LittleEndian};
+
+mod encoder;
+mod decoder;
+pub use self::encoder::encode_tree;
+pub use self::decoder::decode_tree;
+
+pub const CA_FORMAT_ENTRY: u64 = 0x1396fabcea5bbb51;
+pub const CA_FORMAT_USER: u64 = 0xf453131aaeeaccb3;
+pub const CA_FORMAT_GROUP: u64 = 0x25eb6ac969396a52;
+pub const CA_FORMAT_SYMLINK: u64 = 0x664a6fb6830e0d6c;
+pub const CA_FORMAT_DEVICE: u64 = 0xac3dace369dfe643;
+pub const CA_FORMAT_PAYLOAD: u64 = 0x8b9e1d93d6dcffc9;
+pub const CA_FORMAT_FILENAME: u64 = 0x6dbb6ebcb3161f0b;
+pub const CA_FORMAT_GOODBYE: u64 = 0xdfd35c5e8327c403;
+pub const CA_FORMAT_GOODBYE_TAIL_MARKER: u64 = 0x57446fa533702943;
+
+// Feature flags of the metadata kept in archives written here
+pub const CA_FORMAT_WITH_32BIT_UIDS: u64 = 0x2;
+pub const CA_FORMAT_WITH_NSEC_TIME: u64 = 0x20;
+pub const CA_FORMAT_WITH_PERMISSIONS: u64 = 0x100;
+pub const CA_FORMAT_WITH_SYMLINKS: u64 = 0x200;
+pub const CA_FORMAT_WITH_DEVICE_NODES: u64 = 0x400;
+pub const CA_FORMAT_WITH_FIFOS: u64 = 0x800;
+pub const CA_FORMAT_WITH_SOCKETS: u64 = 0x1000;
+pub const ARCHIVE_FEATURE_FLAGS: u64 = CA_FORMAT_WITH_32BIT_UIDS | CA_FORMAT_WITH_NSEC_TIME | CA_FORMAT_WITH_PERMISSIONS |
+    CA_FORMAT_WITH_SYMLINKS | CA_FORMAT_WITH_DEVICE_NODES | CA_FORMAT_WITH_FIFOS | CA_FORMAT_WITH_SOCKETS;
+
+pub const HEADER_SIZE: u64 = 16;
+pub const ENTRY_SIZE: u64 = 64;
+pub const GOODBYE_ITEM_SIZE: u64 = 24;
+
+// Key for the SipHash-2-4 of file names in goodbye tables
+const GOODBYE_HASH_KEY: (u64, u64) = (0x8574442b0f1d84b3, 0x2736ed30d1c22ec1);
+
+// GoodbyeItem Locates a child of a directory, offsets count back from the goodbye item
+#[derive(Clone, Copy)]
+pub struct GoodbyeItem {
+    pub offset: u64,
+    pub size: u64,
+    pub hash: u64
+}
+
+#[allow(deprecated)]
+pub fn filename_hash(name: &[u8]) -> u64 {
+    use std::hash::{Hasher, SipHasher};
+    let mut hasher = SipHasher::new_with_keys(GOODBYE_HASH_KEY.0, GOODBYE_HASH_KEY.1);
+    hasher.write(name);
+    hasher.finish()
+}
+
+// Arranges items sorted by hash as a complete binary search tree in array form, children of
+// item i at 2i+1 and 2i+2, the layout casync searches goodbye tables in
+pub fn make_bst(sorted: &[GoodbyeItem], output: &mut Vec<GoodbyeItem>, i: usize) {
+    if sorted.is_empty() {
+        return;
+    }
+    let n = sorted.len();
+    // Size of the left subtree of a complete tree with n nodes, p is the largest power of two <= n
+    let p = if n.is_power_of_two() { n } else { n.next_power_of_two() / 2 };
+    let left = if p == 1 { 0 } else { p / 2 - 1 + std::cmp::min(n - p + 1, p / 2) };
+    output[i] = sorted[left];
+    make_bst(&sorted[..left], output, 2 * i + 1);
+    make_bst(&sorted[left + 1..], output, 2 * i + 2);
+}
+
+pub fn write_header<W: Write>(out: &mut W, size: u64, item_type: u64) {
+    let mut buf = [0; HEADER_SIZE as usize];
+    LittleEndian::write_u64(&mut buf[..8], size);
+    LittleEndian::write_u64(&mut buf[8..], item_type);
+    out.write_all(&buf).expect("Error: Cannot write archive");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const MTIME_NS: u64 = 1_500_000_000_000_000_123;
+
+    // A directory holding "a" with "hi\n" and an empty "b", the archive written by casync
+    // for it. Assembled by hand from the values in casync's caformat.h, so it does not
+    // depend on the encoder under test.
+    fn golden_archive(uid: u64, gid: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        let u64s = |out: &mut Vec<u8>, values: &[u64]| {
+            for v in values {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        };
+        let flags = 0x2 | 0x20 | 0x100 | 0x200 | 0x400 | 0x800 | 0x1000;
+        // 0: ENTRY of the directory
+        u64s(&mut out, &[64, 0x1396fabcea5bbb51, flags, 0o40755, 0, uid, gid, MTIME_NS]);
+        // 64: FILENAME "a", 82: its ENTRY, 146: PAYLOAD
+        u64s(&mut out, &[18, 0x6dbb6ebcb3161f0b]);
+        out.extend_from_slice(b"a\0");
+        u64s(&mut out, &[64, 0x1396fabcea5bbb51, flags, 0o100644, 0, uid, gid, MTIME_NS]);
+        u64s(&mut out, &[19, 0x8b9e1d93d6dcffc9]);
+        out.extend_from_slice(b"hi\n");
+        // 165: FILENAME "b", 183: its ENTRY, 247: empty PAYLOAD
+        u64s(&mut out, &[18, 0x6dbb6ebcb3161f0b]);
+        out.extend_from_slice(b"b\0");
+        u64s(&mut out, &[64, 0x1396fabcea5bbb51, flags, 0o100644, 0, uid, gid, MTIME_NS]);
+        u64s(&mut out, &[16, 0x8b9e1d93d6dcffc9]);
+        // 263: GOODBYE, items in binary search tree order of their SipHash-2-4 name hashes,
+        // "b" (0x9007695395e7df8f) at the root and "a" (0x51ba5a19e058c7ad) left of it
+        u64s(&mut out, &[88, 0xdfd35c5e8327c403]);
+        u64s(&mut out, &[263 - 165, 263 - 165, 0x9007695395e7df8f]);
+        u64s(&mut out, &[263 - 64, 165 - 64, 0x51ba5a19e058c7ad]);
+        u64s(&mut out, &[263, 88, 0x57446fa533702943]);
+        out
+    }
+
+    fn set_mtime(path: &std::path::Path) {
+        let mtime = UNIX_EPOCH + Duration::from_nanos(MTIME_NS);
+        fs::File::open(path).unwrap().set_modified(mtime).unwrap();
+    }
+
+    #[test]
+    fn filename_hashes_match_casync() {
+        assert_eq!(filename_hash(b"a"), 0x51ba5a19e058c7ad);
+        assert_eq!(filename_hash(b"b"), 0x9007695395e7df8f);
+    }
+
+    #[test]
+    fn encoder_writes_the_casync_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("tree");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("a"), b"hi\n").unwrap();
+        fs::write(root.join("b"), b"").unwrap();
+        for (path, mode) in [(root.join("a"), 0o644), (root.join("b"), 0o644), (root.clone(), 0o755)].iter() {
+            fs::set_permissions(path, fs::Permissions::from_mode(*mode)).unwrap();
+            set_mtime(path);
+        }
+        let metadata = fs::metadata(&root).unwrap();
+
+        let mut archive = Vec::new();
+        let size = encode_tree(&root, &mut archive, ARCHIVE_FEATURE_FLAGS);
+        let golden = golden_archive(metadata.uid() as u64, metadata.gid() as u64);
+        assert_eq!(size, golden.len() as u64);
+        assert_eq!(archive, golden);
+    }
+
+    #[test]
+    fn decoder_restores_the_casync_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("restored");
+        let (uid, gid) = unsafe { (libc::geteuid() as u64, libc::getegid() as u64) };
+        let entries = decode_tree(&golden_archive(uid, gid)[..], &root);
+        assert_eq!(entries, 3);
+        assert_eq!(fs::read(root.join("a")).unwrap(), b"hi\n".to_vec());
+        assert_eq!(fs::read(root.join("b")).unwrap(), Vec::<u8>::new());
+        let metadata = fs::metadata(root.join("a")).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o644);
+        assert_eq!(metadata.mtime() as u64 * 1_000_000_000 + metadata.mtime_nsec() as u64, MTIME_NS);
+    }
+}
@@ -1,21 +1,29 @@
-use std::rc::Rc;
 use std::fs::File;
-use std::io::{ErrorKind};
 use std::io::Read;
-use std::collections::VecDeque;
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 
 use crate::index;
 use crate::store;
 use crate::io;
+use crate::digest::ChunkDigest;
+use crate::progress::{self, ChunkSource};
 use log::{info, debug};
 
 pub struct ChunkerConfig {
-    pub index: Box<index::Index>,
-    pub store: Box<store::Store>,
+    pub index: Box<dyn index::Index>,
+    pub store: Box<dyn store::Store>,
+    // More stores like store, each one gets a worker thread hashing, compressing
+    // and writing chunks while the input is still being scanned
+    pub worker_stores: Vec<Box<dyn store::Store>>,
     pub source: Box<io::LocalSourceFile>,
     pub min_size: u64,
     pub max_size: u64,
-    pub avg_size: u64
+    pub avg_size: u64,
+    // Has to match the digest the stores hash chunks with
+    pub digest: ChunkDigest,
+    pub progress: Box<dyn progress::Progress>
 }
 
 static HASH_TABLE: [u32; 256] = [
@@ -89,78 +97,212 @@ static CHUNKER_WINDOW_SIZE: u8 = 48;
 pub const CHUNK_SIZE_AVG_DEFAULT: u64 = 64 * 1024;
 pub const CHUNK_SIZE_MIN_DEFAULT: u64 = CHUNK_SIZE_AVG_DEFAULT / 4;
 pub const CHUNK_SIZE_MAX_DEFAULT: u64 = CHUNK_SIZE_AVG_DEFAULT * 4;
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
 
 impl ChunkerConfig {
     pub fn chunk(&mut self) {
-        // TODO: move idx init inside loop
-        let mut idx: usize = 0;
-        let mut total_byte_count: u64 = 0;
-        let mut file = Rc::get_mut(&mut self.source.file).unwrap();
-        let discriminator = discriminator_from_avg(CHUNK_SIZE_AVG_DEFAULT);
+        let file: &File = &self.source.file;
+        // Block devices are read up to their size rather than to EOF
+        let size = self.source.size;
+        let discriminator = discriminator_from_avg(self.avg_size);
+        let (min_size, max_size) = (self.min_size, self.max_size);
         self.store.create("");
 
         // Write index header
-        self.index.write_header(self.min_size, self.max_size, self.avg_size);
-
-        loop {
-            let mut chunk_buf: VecDeque<u8> = VecDeque::new();
-            let mut window_rev:Vec<u8> = Vec::new();
-            read_min(file, &mut chunk_buf, &mut window_rev, self.min_size);
-            let win_size = window_rev.len();
-            if win_size != CHUNKER_WINDOW_SIZE as usize {
-                //Write to file and return
-                total_byte_count += chunk_buf.len() as u64;
-                info!(target:"chunker", "EOF reached, Could not find data to fill min chunk size");
+        self.index.write_header(self.min_size, self.max_size, self.avg_size, self.digest);
+
+        let index = &mut self.index;
+        let progress = &mut self.progress;
+        progress.start(size);
+        let total_byte_count = if self.worker_stores.is_empty() {
+            let store = &mut self.store;
+            find_chunks(file.take(size), min_size, max_size, discriminator, &mut |end, chunk| {
+                progress.chunk_done(chunk.len() as u64, ChunkSource::Input);
+                let hash_bytes = store.write_item(chunk);
+                index.add_entry(end, hash_bytes);
+            })
+        } else {
+            let mut stores: Vec<&mut Box<dyn store::Store>> = vec![&mut self.store];
+            stores.extend(self.worker_stores.iter_mut());
+            chunk_parallel(stores, index, |emit| find_chunks(file.take(size), min_size, max_size, discriminator, &mut |end, chunk| {
+                progress.chunk_done(chunk.len() as u64, ChunkSource::Input);
+                emit(end, chunk);
+            }))
+        };
+        self.progress.finish();
+        self.index.write_tail();
+        info!(target:"chunker", "Done processing chunks from {} of size {:?}", self.source.path, total_byte_count);
+    }
+}
+
+// Chunks the first size bytes of file without storing anything, returns where each chunk is
+pub fn find_chunk_ids(file: &File, size: u64, min_size: u64, max_size: u64, avg_size: u64, digest: ChunkDigest) -> Vec<index::ChunkData> {
+    let discriminator = discriminator_from_avg(avg_size);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    find_chunks(file.take(size), min_size, max_size, discriminator, &mut |end, chunk| {
+        chunks.push(index::ChunkData { id: digest.sum(&chunk), start, size: end - start });
+        start = end;
+    });
+    chunks
+}
+
+// Sizes of the chunks a long run of zeros is cut into. The window holds only zeros, so the
+// rolling hash never changes and every cut of the run lands at the same distance from the last.
+// The chunk at the end of a blob is cut by the end of the input instead and can have any size.
+pub fn zero_chunk_sizes(min_size: u64, max_size: u64, avg_size: u64) -> Vec<u64> {
+    let mut sizes = Vec::new();
+    if min_size < CHUNKER_WINDOW_SIZE as u64 || max_size <= min_size || avg_size == 0 {
+        return sizes;
+    }
+    let mut start = 0;
+    let run = std::io::repeat(0).take(2 * max_size + min_size);
+    find_chunks(run, min_size, max_size, discriminator_from_avg(avg_size), &mut |end, _| {
+        sizes.push(end - start);
+        start = end;
+    });
+    // The last one was cut short by the end of the run
+    sizes.pop();
+    sizes.sort();
+    sizes.dedup();
+    sizes
+}
+
+// Runs one worker per store which hashes, compresses and writes the chunks found by scan,
+// chunk ids are added to the index in the order the chunks were found
+fn chunk_parallel<F>(stores: Vec<&mut Box<dyn store::Store>>, index: &mut Box<dyn index::Index>, scan: F) -> u64
+    where F: FnOnce(&mut dyn FnMut(u64, Vec<u8>)) -> u64
+{
+    // Bounded, so the chunker can't run far ahead of the workers and fill memory
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel::<(u64, u64, Vec<u8>)>(stores.len() * 2);
+    let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+    let (id_tx, id_rx) = mpsc::channel::<(u64, u64, [u8;32])>();
+    info!(target:"chunker", "Chunking with {} workers", stores.len());
+
+    thread::scope(|scope| {
+        for store in stores {
+            let chunk_rx = chunk_rx.clone();
+            let id_tx = id_tx.clone();
+            scope.spawn(move || {
+                loop {
+                    let next = chunk_rx.lock().unwrap().recv();
+                    match next {
+                        Ok((seq, end, chunk)) => {
+                            let hash_bytes = store.write_item(chunk);
+                            id_tx.send((seq, end, hash_bytes)).unwrap();
+                        },
+                        Err(_) => break
+                    }
+                }
+            });
+        }
+        // Only workers hold these now, sends fail if all of them are gone
+        drop(chunk_rx);
+        drop(id_tx);
+
+        let mut pending: HashMap<u64, (u64, [u8;32])> = HashMap::new();
+        let mut next_seq: u64 = 0;
+        let mut seq: u64 = 0;
+        let total_byte_count = scan(&mut |end, chunk| {
+            if chunk_tx.send((seq, end, chunk)).is_err() {
+                panic!("Chunk workers stopped unexpectedly");
+            }
+            seq += 1;
+            while let Ok((done_seq, done_end, hash_bytes)) = id_rx.try_recv() {
+                pending.insert(done_seq, (done_end, hash_bytes));
+            }
+            add_in_order(&mut pending, &mut next_seq, index);
+        });
+        drop(chunk_tx);
+        for (done_seq, done_end, hash_bytes) in id_rx.iter() {
+            pending.insert(done_seq, (done_end, hash_bytes));
+            add_in_order(&mut pending, &mut next_seq, index);
+        }
+        if next_seq != seq {
+            panic!("Only {} of {} chunks were written", next_seq, seq);
+        }
+        total_byte_count
+    })
+}
+
+fn add_in_order(pending: &mut HashMap<u64, (u64, [u8;32])>, next_seq: &mut u64, index: &mut Box<dyn index::Index>) {
+    while let Some((end, hash_bytes)) = pending.remove(next_seq) {
+        index.add_entry(end, hash_bytes);
+        *next_seq += 1;
+    }
+}
+
+// Finds chunk boundaries in source and hands each chunk with its end offset to emit,
+// returns the number of bytes read
+fn find_chunks<R: Read>(source: R, min_size: u64, max_size: u64, discriminator: u32, emit: &mut dyn FnMut(u64, Vec<u8>)) -> u64 {
+    // TODO: move idx init inside loop
+    let mut idx: usize = 0;
+    let mut total_byte_count: u64 = 0;
+    let mut bytes = std::io::BufReader::with_capacity(READ_BUFFER_SIZE, source).bytes();
+
+    loop {
+        let mut chunk_buf: Vec<u8> = Vec::new();
+        let mut window_rev:Vec<u8> = Vec::new();
+        read_min(&mut bytes, &mut chunk_buf, &mut window_rev, min_size);
+        let win_size = window_rev.len();
+        if win_size != CHUNKER_WINDOW_SIZE as usize {
+            total_byte_count += chunk_buf.len() as u64;
+            info!(target:"chunker", "EOF reached, Could not find data to fill min chunk size");
+            // Input ending right at a boundary, or empty input, leaves nothing for a last chunk.
+            // casync writes no entry for it, neither does this.
+            if !chunk_buf.is_empty() {
                 debug!("Chunk found with size {:?}, offset {:?}", chunk_buf.len(), total_byte_count);
-                let hash_bytes = self.store.write_item(Vec::from(chunk_buf));
-                self.index.add_entry(total_byte_count, hash_bytes);
-                self.index.write_tail();
-                info!(target:"chunker", "Done processing chunks from file size {:?}", total_byte_count);
-                break;
+                emit(total_byte_count, chunk_buf);
             }
-            // Reversing window to get it in actual order
-            let mut window: Vec<u8> = Vec::new();
-            for v in window_rev.iter() {
-                window.push(*v);
+            break;
+        }
+        // Reversing window to get it in actual order
+        let mut window: Vec<u8> = Vec::new();
+        for v in window_rev.iter() {
+            window.push(*v);
+        }
+        let mut hash = hash(&window);
+
+        //TODO: buf_size can be removed in favor of chunk_buf.len()?
+        let mut buf_size = min_size;
+        total_byte_count += buf_size;
+        let mut boundary_found = false;
+        for v in bytes.by_ref() {
+            // Remove first element
+            let in_byte = v.unwrap();
+            let out_byte = window[idx];
+            window[idx] = in_byte;
+            idx = (idx + 1) % (CHUNKER_WINDOW_SIZE as usize);
+            hash = hash.rotate_left(1) ^ HASH_TABLE[out_byte as usize].rotate_left(CHUNKER_WINDOW_SIZE as u32) ^ HASH_TABLE[in_byte as usize];
+            chunk_buf.push(in_byte);
+            buf_size += 1;
+            total_byte_count += 1;
+
+            if buf_size >= max_size {
+                idx = 0;
+                boundary_found = true;
+                debug!("Chunk found with max size: {:?}, offset: {:?}", buf_size, total_byte_count);
+                break;
             }
-            let mut hash = hash(&window);
-
-            //TODO: buf_size can be removed in favor of chunk_buf.len()?
-            let mut buf_size = self.min_size;
-            total_byte_count += buf_size as u64;
-            for (_i, v) in file.try_clone().unwrap().bytes().enumerate() {
-                // Remove first element
-                let in_byte = v.unwrap();
-                let out_byte = window[idx];
-                window[idx] = in_byte;
-                idx = (idx + 1) % (CHUNKER_WINDOW_SIZE as usize);
-                hash = hash.rotate_left(1) ^ HASH_TABLE[out_byte as usize].rotate_left(CHUNKER_WINDOW_SIZE as u32) ^ HASH_TABLE[in_byte as usize];
-                chunk_buf.push_back(in_byte);
-                buf_size = buf_size + 1;
-                total_byte_count += 1;
-
-                if buf_size >= self.max_size {
-                    idx = 0;
-                    let hash_bytes = self.store.write_item(Vec::from(chunk_buf));
-                    self.index.add_entry(total_byte_count, hash_bytes);
-                    debug!("Chunk found with max size: {:?}, offset: {:?}", buf_size, total_byte_count);
-                    break;
-                }
 
-                if (hash % discriminator) == (discriminator-1) {
-                    idx = 0;
-                    let hash_bytes = self.store.write_item(Vec::from(chunk_buf));
-                    self.index.add_entry(total_byte_count, hash_bytes);
-                    debug!("Chunk found with size: {:?}, offset {:?}", buf_size, total_byte_count);
-                    break;
-                }
+            if (hash % discriminator) == (discriminator-1) {
+                idx = 0;
+                boundary_found = true;
+                debug!("Chunk found with size: {:?}, offset {:?}", buf_size, total_byte_count);
+                break;
             }
-            //TODO: Handle EOF when buf_size > min but less than max or discriminator val cannot found?
+        }
+        emit(total_byte_count, chunk_buf);
+        if !boundary_found {
+            // EOF between min and max size without a boundary, the rest is the last chunk
+            debug!("Last chunk found with size: {:?}, offset {:?}", buf_size, total_byte_count);
+            break;
         }
     }
+    total_byte_count
 }
 
-fn hash(window: &Vec<u8>) -> u32 {
+fn hash(window: &[u8]) -> u32 {
     let mut hash:u32 = 0;
     for (i, v) in window.iter().enumerate() {
         hash ^= HASH_TABLE.get((*v) as usize).unwrap().rotate_left((CHUNKER_WINDOW_SIZE as u32)-(i as u32)-1);
@@ -174,8 +316,8 @@ fn discriminator_from_avg(avg: u64) -> u32 {
 }
 
 
-pub fn read_min(f: &mut File, buf: &mut VecDeque<u8>, window: &mut Vec<u8>, min_size: u64) {
-    for (i, v) in f.bytes().enumerate() {
+pub fn read_min<I: Iterator<Item=std::io::Result<u8>>>(bytes: &mut I, buf: &mut Vec<u8>, window: &mut Vec<u8>, min_size: u64) {
+    for (i, v) in bytes.by_ref().enumerate() {
         match v {
             Ok(val) => {
                 let rem = min_size-(i as u64)-1;
@@ -183,7 +325,7 @@ pub fn read_min(f: &mut File, buf: &mut VecDeque<u8>, window: &mut Vec<u8>, min_
                     // Fill window
                     window.push(val);
                 }
-                buf.push_back(val);
+                buf.push(val);
                 if rem == 0 {
                     break;
                 }
@@ -194,3 +336,125 @@ pub fn read_min(f: &mut File, buf: &mut VecDeque<u8>, window: &mut Vec<u8>, min_
         }
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::progress::NoProgress;
+
+    const MIN: u64 = 256;
+    const AVG: u64 = 1024;
+    const MAX: u64 = 4096;
+
+    // Reproducible data which chunks at content defined boundaries
+    pub fn random_bytes(size: usize) -> Vec<u8> {
+        let mut state: u64 = 0x9e3779b97f4a7c15;
+        (0..size).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 24) as u8
+        }).collect()
+    }
+
+    fn chunks_of(data: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        let mut chunks = Vec::new();
+        let total = find_chunks(data, MIN, MAX, discriminator_from_avg(AVG), &mut |end, chunk| chunks.push((end, chunk)));
+        assert_eq!(total, data.len() as u64);
+        chunks
+    }
+
+    #[test]
+    fn chunks_cover_the_input() {
+        let data = random_bytes(200_000);
+        let chunks = chunks_of(&data);
+        assert!(chunks.len() > 20);
+        let mut start = 0;
+        for (i, (end, chunk)) in chunks.iter().enumerate() {
+            assert_eq!(&data[start..*end as usize], &chunk[..]);
+            if i + 1 < chunks.len() {
+                assert!(chunk.len() as u64 > MIN && chunk.len() as u64 <= MAX, "chunk of {} bytes", chunk.len());
+            }
+            start = *end as usize;
+        }
+        assert_eq!(start, data.len());
+    }
+
+    #[test]
+    fn input_ending_between_boundaries_keeps_its_last_chunk() {
+        let data = random_bytes(200_000);
+        let first_end = chunks_of(&data)[0].0 as usize;
+        // Cut after min size but before the next boundary is found
+        let cut = &data[..first_end + MIN as usize + 10];
+        let chunks = chunks_of(cut);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].1, &cut[first_end..]);
+    }
+
+    #[test]
+    fn input_ending_on_a_boundary_gets_no_empty_last_chunk() {
+        let data = random_bytes(200_000);
+        let first_end = chunks_of(&data)[0].0 as usize;
+        let chunks = chunks_of(&data[..first_end]);
+        assert_eq!(chunks.iter().map(|(end, c)| (*end, c.len())).collect::<Vec<_>>(), vec![(first_end as u64, first_end)]);
+        assert_eq!(chunks_of(&[]), Vec::new());
+    }
+
+    #[test]
+    fn zero_runs_are_cut_into_chunks_of_one_size() {
+        let sizes = zero_chunk_sizes(MIN, MAX, AVG);
+        assert_eq!(sizes.len(), 1);
+        let data = vec![0; 10 * MAX as usize];
+        let chunks = chunks_of(&data);
+        for (_, chunk) in chunks[..chunks.len() - 1].iter() {
+            assert_eq!(chunk.len() as u64, sizes[0]);
+        }
+    }
+
+    fn make_index(dir: &std::path::Path, input: &str, name: &str, workers: usize) -> Vec<u8> {
+        let index_path = dir.join(name);
+        let store_path = String::from(dir.join("store").to_str().unwrap());
+        let new_store = || -> Box<dyn store::Store> {
+            let mut store = store::LocalStore::new(&store_path, MIN, MAX, AVG);
+            // Fast level, the test is about chunk order not compression
+            store.compression = store::ChunkCompression::Zstd(1);
+            Box::new(store)
+        };
+        let mut config = ChunkerConfig {
+            index: Box::new(index::LocalIndexFile::new(index_path.to_str().unwrap())),
+            store: new_store(),
+            worker_stores: (0..workers).map(|_| new_store()).collect(),
+            source: Box::new(io::LocalSourceFile::new(String::from(input))),
+            min_size: CHUNK_SIZE_MIN_DEFAULT,
+            max_size: CHUNK_SIZE_MAX_DEFAULT,
+            avg_size: CHUNK_SIZE_AVG_DEFAULT,
+            digest: ChunkDigest::default(),
+            progress: Box::new(NoProgress)
+        };
+        config.chunk();
+        drop(config);
+        std::fs::read(index_path).unwrap()
+    }
+
+    #[test]
+    fn empty_input_gets_no_index_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        std::fs::File::create(&input).unwrap();
+        let input = input.to_str().unwrap();
+        // Header, chunk table header and tail, without a single entry
+        assert_eq!(make_index(dir.path(), input, "sequential.caibx", 0).len(), 48 + 16 + 40);
+        assert_eq!(make_index(dir.path(), input, "parallel.caibx", 3).len(), 48 + 16 + 40);
+    }
+
+    #[test]
+    fn parallel_chunking_writes_the_same_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        std::fs::File::create(&input).unwrap().write_all(&random_bytes(1_500_000)).unwrap();
+        let input = input.to_str().unwrap();
+        let sequential = make_index(dir.path(), input, "sequential.caibx", 0);
+        assert_eq!(make_index(dir.path(), input, "parallel.caibx", 3), sequential);
+    }
+}
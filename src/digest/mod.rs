@@ -0,0 +1,54 @@
+use crypto::sha2::{Sha256, Sha512Trunc256};
+use crypto::digest::Digest;
+
+// casync feature flags, an index without CA_FORMAT_SHA512256 uses SHA256 chunk ids
+pub const CA_FORMAT_SHA512256: u64 = 0x2000000000000000;
+pub const CA_FORMAT_EXCLUDE_NO_DUMP: u64 = 0x8000000000000000;
+
+// ChunkDigest Hash algorithm deriving chunk ids from chunk data
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ChunkDigest {
+    #[default]
+    SHA512256,
+    SHA256
+}
+
+impl ChunkDigest {
+    // Names as accepted by --digest
+    pub fn from_name(name: &str) -> ChunkDigest {
+        match name.to_lowercase().as_str() {
+            "sha512-256" | "sha512/256" => ChunkDigest::SHA512256,
+            "sha256" => ChunkDigest::SHA256,
+            _ => {
+                panic!("Unknown digest {}, use sha512-256 or sha256", name);
+            }
+        }
+    }
+
+    pub fn from_feature_flags(flags: u64) -> ChunkDigest {
+        if flags & CA_FORMAT_SHA512256 != 0 {
+            ChunkDigest::SHA512256
+        } else {
+            ChunkDigest::SHA256
+        }
+    }
+
+    // Feature flags casync writes into blob indexes using this digest
+    pub fn feature_flags(&self) -> u64 {
+        match self {
+            ChunkDigest::SHA512256 => CA_FORMAT_EXCLUDE_NO_DUMP | CA_FORMAT_SHA512256,
+            ChunkDigest::SHA256 => CA_FORMAT_EXCLUDE_NO_DUMP
+        }
+    }
+
+    pub fn sum(&self, bytes: &[u8]) -> [u8;32] {
+        let mut hasher: Box<dyn Digest> = match self {
+            ChunkDigest::SHA512256 => Box::new(Sha512Trunc256::new()),
+            ChunkDigest::SHA256 => Box::new(Sha256::new())
+        };
+        hasher.input(bytes);
+        let mut hash_bytes: [u8;32] = [0;32];
+        hasher.result(&mut hash_bytes);
+        hash_bytes
+    }
+}
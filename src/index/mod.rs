@@ -1,20 +1,38 @@
 use std::fs::File;
 use std::rc::Rc;
 use crate::utils;
+use crate::store;
+use crate::digest::ChunkDigest;
 use log::{info, debug, error};
-use std::io::{ErrorKind, Error};
+use std::io::ErrorKind;
+use std::io::{Write, Seek, SeekFrom};
+use url::Url;
 
-const CaFormatIndex: u64 = 0x96824d9c7b129ff9;
-const CaFormatTable: u64 = 0xe75b9e112f17417d;
-const CaFormatSHA512256: u64 = 0x2000000000000000;
-const CaFormatTableTailMarker: u64 = 0x4b4f050e5549ecd1;
-const FeatureFlags: u64 = 11529215046068469760;
+const CA_FORMAT_INDEX: u64 = 0x96824d9c7b129ff9;
+const CA_FORMAT_TABLE: u64 = 0xe75b9e112f17417d;
+// Feature flags below the digest and exclude flags describe catar metadata, blob indexes have none
+const CA_FORMAT_WITH_MASK: u64 = 0x0fffffffffffffff;
+const CA_FORMAT_TABLE_TAIL_MARKER: u64 = 0x4b4f050e5549ecd1;
+// Sizes of the parts of an index file
+const INDEX_HEADER_SIZE: u64 = 48;
+const TABLE_HEADER_SIZE: u64 = 16;
+const TABLE_ITEM_SIZE: u64 = 40;
+const TABLE_TAIL_SIZE: u64 = 40;
 
 pub struct LocalIndexFile {
     pub path: String,
     pub file: Rc<File>,
     pub chunk_table_size: u64,
-    pub chunk_data: Vec<ChunkData>
+    // Chunks read from the file, or added so far when writing it
+    pub chunk_data: Vec<ChunkData>,
+    // Taken from the feature flags on read
+    pub digest: ChunkDigest,
+    // catar feature flags of .caidx indexes, written next to the digest flag, 0 for blobs
+    pub feature_flags: u64,
+    // Chunk size limits from the header
+    pub chunk_size_min: u64,
+    pub chunk_size_avg: u64,
+    pub chunk_size_max: u64
 }
 
 impl LocalIndexFile {
@@ -25,7 +43,12 @@ impl LocalIndexFile {
                     path: String::from(path),
                     file: Rc::new(f),
                     chunk_table_size: 0,
-                    chunk_data: Vec::new()
+                    chunk_data: Vec::new(),
+                    digest: ChunkDigest::default(),
+                    feature_flags: 0,
+                    chunk_size_min: 0,
+                    chunk_size_avg: 0,
+                    chunk_size_max: 0
                 }
             },
             Err(e) => {
@@ -40,7 +63,12 @@ impl LocalIndexFile {
                     path: String::from(path),
                     file: Rc::new(f),
                     chunk_table_size: 0,
-                    chunk_data: Vec::new()
+                    chunk_data: Vec::new(),
+                    digest: ChunkDigest::default(),
+                    feature_flags: 0,
+                    chunk_size_min: 0,
+                    chunk_size_avg: 0,
+                    chunk_size_max: 0
                 }
             },
             Err(e) => {
@@ -48,132 +76,190 @@ impl LocalIndexFile {
             }
         }
     }
+
+    // Whether the index describes a catar archive (.caidx) rather than a blob (.caibx),
+    // only known once the index was read
+    pub fn is_archive(&self) -> bool {
+        self.feature_flags & CA_FORMAT_WITH_MASK != 0 || self.path.ends_with(".caidx")
+    }
+}
+
+// Opens an index from a local path, or downloads it when given an http(s) url. Anything else
+// is a local path, even if it parses as a url like foo:bar.
+pub fn open_index(path: &str, options: &store::StoreOptions) -> LocalIndexFile {
+    if !is_remote(path) {
+        return LocalIndexFile::open(path);
+    }
+    info!("Fetching remote index {}", path);
+    let data = store::RemoteHTTPClient::new(options).get(path);
+    match tempfile::tempfile() {
+        Ok(mut f) => {
+            f.write_all(&data).expect("Error: Cannot write remote index to temporary file");
+            f.seek(SeekFrom::Start(0)).unwrap();
+            LocalIndexFile {
+                path: String::from(path),
+                file: Rc::new(f),
+                chunk_table_size: 0,
+                chunk_data: Vec::new(),
+                digest: ChunkDigest::default(),
+                feature_flags: 0,
+                chunk_size_min: 0,
+                chunk_size_avg: 0,
+                chunk_size_max: 0
+            }
+        },
+        Err(e) => {
+            panic!("Could not create temporary file for remote index, {:?}", e);
+        }
+    }
+}
+
+fn is_remote(path: &str) -> bool {
+    Url::parse(path).is_ok_and(|url| url.scheme() == "http" || url.scheme() == "https")
 }
 
 pub trait Index {
     // Load index file for extract
     // Add new index entry
-    fn write_header(&mut self, min: u64, max: u64, avg: u64);
+    fn write_header(&mut self, min: u64, max: u64, avg: u64, digest: ChunkDigest);
     fn add_entry(&mut self, start: u64, chunk_id: [u8;32]);
     fn write_tail(&mut self);
     //TODO: rename to load
     fn read(&mut self);
-    fn getChunkData(&self) -> Vec<ChunkData>;
+    fn get_chunk_data(&self) -> Vec<ChunkData>;
+    // Hash the chunk ids were derived with
+    fn digest(&self) -> ChunkDigest;
+    // Smallest, average and largest chunk size the chunker was configured with
+    fn chunk_sizes(&self) -> (u64, u64, u64);
 }
 
 impl Index for LocalIndexFile {
-    fn write_header(&mut self, min: u64, max: u64, avg: u64) {
+    fn write_header(&mut self, min: u64, max: u64, avg: u64, digest: ChunkDigest) {
         info!("Started writing to index file");
-        let size = 48;
-        let mut file = Rc::get_mut(&mut self.file).unwrap();
+        self.digest = digest;
+        let size = INDEX_HEADER_SIZE;
+        let file = Rc::get_mut(&mut self.file).unwrap();
         utils::write_u64(file, size).unwrap();
-        utils::write_u64(file, CaFormatIndex).unwrap();
-        utils::write_u64(file, FeatureFlags).unwrap();
+        utils::write_u64(file, CA_FORMAT_INDEX).unwrap();
+        utils::write_u64(file, self.feature_flags | digest.feature_flags()).unwrap();
         utils::write_u64(file, min).unwrap();
         utils::write_u64(file, avg).unwrap();
         utils::write_u64(file, max).unwrap();
         // Header for chunks
-        utils::write_u64(file, std::u64::MAX);
+        utils::write_u64(file, u64::MAX).unwrap();
         self.chunk_table_size += 8;
-        utils::write_u64(file, CaFormatTable);
+        utils::write_u64(file, CA_FORMAT_TABLE).unwrap();
         self.chunk_table_size += 8;
         debug!("Wrote header to index file");
     }
     fn add_entry(&mut self, start: u64, chunk_id: [u8;32]) {
-        let mut file = Rc::get_mut(&mut self.file).unwrap();
+        let file = Rc::get_mut(&mut self.file).unwrap();
         utils::write_u64(file, start).unwrap();
         self.chunk_table_size += 8;
         utils::write_32_bytes(file, chunk_id).unwrap();
         self.chunk_table_size += 32;
+        let last_end = self.chunk_data.last().map_or(0, |c| c.start + c.size);
+        self.chunk_data.push(ChunkData { id: chunk_id, start: last_end, size: start - last_end });
         debug!("Added chunk entry to index file");
     }
     fn write_tail(&mut self) {
-        let mut file = Rc::get_mut(&mut self.file).unwrap();
+        let file = Rc::get_mut(&mut self.file).unwrap();
         utils::write_u64(file, 0).unwrap();
         utils::write_u64(file, 0).unwrap();
         utils::write_u64(file, 48).unwrap();
         utils::write_u64(file, self.chunk_table_size + 40).unwrap();
-        utils::write_u64(file, CaFormatTableTailMarker).unwrap();
-        self.chunk_table_size += (5 * 8);
+        utils::write_u64(file, CA_FORMAT_TABLE_TAIL_MARKER).unwrap();
+        self.chunk_table_size += 5 * 8;
         debug!("Wrote tail marker to index file");
         info!("Finished writing to index file");
     }
 
     fn read(&mut self) {
-        // read file
-        let mut f = Rc::get_mut(&mut self.file).unwrap();
-        let _headerSize = utils::read_u64(&mut f);
-        let headertype = utils::read_u64(&mut f);
-        let mut chunkItems: Vec<ChunkData> = Vec::new();
-        if headertype == CaFormatIndex {
+        // read file, from the start so an index can be read again
+        let f = Rc::get_mut(&mut self.file).unwrap();
+        f.seek(SeekFrom::Start(0)).expect("Error: Cannot seek in index file");
+        self.chunk_table_size = 0;
+        let _header_size = utils::read_u64(f);
+        let headertype = utils::read_u64(f);
+        let mut chunk_items: Vec<ChunkData> = Vec::new();
+        if headertype == CA_FORMAT_INDEX {
             // Reading index file
             info!("Found index file");
-            let indexFeatureFlags = utils::read_u64(&mut f);
-            let indexChunkSizeMin = utils::read_u64(&mut f);
-            let indexChunkSizeAvg = utils::read_u64(&mut f);
-            let indexChunkSizeMax = utils::read_u64(&mut f);
+            let index_feature_flags = utils::read_u64(f);
+            let index_chunk_size_min = utils::read_u64(f);
+            let index_chunk_size_avg = utils::read_u64(f);
+            let index_chunk_size_max = utils::read_u64(f);
 
-            if (indexFeatureFlags & CaFormatSHA512256) as u64 == 0 {
-                panic!("Only supports SHA 512 / 256")
-            }
+            self.digest = ChunkDigest::from_feature_flags(index_feature_flags);
+            self.feature_flags = index_feature_flags;
+            self.chunk_size_min = index_chunk_size_min;
+            self.chunk_size_avg = index_chunk_size_avg;
+            self.chunk_size_max = index_chunk_size_max;
+            info!("Index uses {:?} chunk ids", self.digest);
 
             // Reading chunk table
-            let headerSize = utils::read_u64(&mut f);
-            let headertype = utils::read_u64(&mut f);
-            if headerSize != std::u64::MAX {
+            let header_size = utils::read_u64(f);
+            let headertype = utils::read_u64(f);
+            if header_size != u64::MAX {
                 panic!("Invalid size");
             }
-            if headertype == CaFormatTable {
-                let mut tableItems: Vec<TableItem> = Vec::new();
-                loop {
-                    let offset = utils::read_u64(&mut f);
-                    if offset == 0 {
-                        break;
-                    }
+            if headertype == CA_FORMAT_TABLE {
+                let mut table_items: Vec<TableItem> = Vec::new();
+                // Entries are counted from the file size. Older versions wrote an entry ending at
+                // offset 0 for empty input, looking for the 0 starting the tail would stop there.
+                let file_size = f.metadata().expect("Error: Cannot read index size").len();
+                let entries = file_size.saturating_sub(INDEX_HEADER_SIZE + TABLE_HEADER_SIZE + TABLE_TAIL_SIZE) / TABLE_ITEM_SIZE;
+                for _ in 0..entries {
+                    let offset = utils::read_u64(f);
                     let mut chunk_id: [u8;32] = [0;32];
-                    match utils::read_32_bytes(&mut f, &mut chunk_id) {
+                    match utils::read_32_bytes(f, &mut chunk_id) {
                         Ok(()) => {
-                            tableItems.push(TableItem{
-                                offset: offset,
+                            table_items.push(TableItem{
+                                offset,
                                 id: chunk_id 
                             })
                         },
+                        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                            break;
+                        },
                         Err(e) => {
-                            if e.kind() == ErrorKind::UnexpectedEof {
-                                break;
-                            }
-                        }
-                        Err(e) => {
-                            error!("Error while reading chunk id {}",e)
+                            error!("Error while reading chunk id {}",e);
+                            break;
                         }
                     }
                 }
-                debug!("Number of chunks found {}", tableItems.len());
-                let tailMarker1 = utils::read_u64(&mut f);
-                if tailMarker1 != 0 {
+                debug!("Number of chunks found {}", table_items.len());
+                // The tail starts with two zeros
+                let tail_marker0 = utils::read_u64(f);
+                let tail_marker1 = utils::read_u64(f);
+                if tail_marker0 != 0 || tail_marker1 != 0 {
                     panic!("tail marker 1 not found");
                 }
-                utils::read_u64(&mut f);// Read index offset
-                utils::read_u64(&mut f);// size
-                let tailMarker2 = utils::read_u64(&mut f);
-                if tailMarker2 != CaFormatTableTailMarker {
+                utils::read_u64(f);// Read index offset
+                utils::read_u64(f);// size
+                let tail_marker2 = utils::read_u64(f);
+                if tail_marker2 != CA_FORMAT_TABLE_TAIL_MARKER {
                     panic!("tail marker 2 is not found")
                 }
 
                 // Reversing and putting chunks in proper order
-                let mut lastOffset: u64 = 0;
-                for c in tableItems.iter() {
-                    let size = c.offset - lastOffset;
-                    debug!("chunk start {}, size {} and id {:?}", lastOffset, size, c.id);   
-                    chunkItems.push(ChunkData{
+                let mut last_offset: u64 = 0;
+                for c in table_items.iter() {
+                    if c.offset == last_offset {
+                        // Empty chunk, holds nothing to assemble
+                        continue;
+                    }
+                    let size = c.offset - last_offset;
+                    debug!("chunk start {}, size {} and id {:?}", last_offset, size, c.id);   
+                    chunk_items.push(ChunkData{
                         id: c.id,
-                        start: lastOffset,
-                        size: size
+                        start: last_offset,
+                        size
                     });
-                    lastOffset = c.offset;
+                    last_offset = c.offset;
                     self.chunk_table_size += 1;
                 }
-                self.chunk_data = chunkItems;
+                self.chunk_data = chunk_items;
             } else {
                 error!("Invalid chunk table found inside index")
             }
@@ -181,15 +267,21 @@ impl Index for LocalIndexFile {
             error!("Not an index file");
         }
     }
-    fn getChunkData(&self) -> Vec<ChunkData> {
+    fn get_chunk_data(&self) -> Vec<ChunkData> {
         self.chunk_data.clone()
     }
+    fn digest(&self) -> ChunkDigest {
+        self.digest
+    }
+    fn chunk_sizes(&self) -> (u64, u64, u64) {
+        (self.chunk_size_min, self.chunk_size_avg, self.chunk_size_max)
+    }
 }
 
 pub struct InMemoryIndex {
     pub chunk_table_size: u64,
     pub chunk_data: Vec<ChunkData>,
-    pub lastOffset: u64
+    pub last_offset: u64
 }
 
 impl InMemoryIndex {
@@ -197,25 +289,25 @@ impl InMemoryIndex {
         InMemoryIndex {
             chunk_table_size: 0,
             chunk_data: Vec::new(),
-            lastOffset: 0
+            last_offset: 0
         }
     }
 }
 
 impl Index for InMemoryIndex {
-    fn write_header(&mut self, min: u64, max: u64, avg: u64) {
+    fn write_header(&mut self, _min: u64, _max: u64, _avg: u64, _digest: ChunkDigest) {
     }
     fn add_entry(&mut self, start: u64, chunk_id: [u8;32]) {
-        let mut chunkItems: Vec<ChunkData> = Vec::new();
+        let mut chunk_items: Vec<ChunkData> = Vec::new();
         self.chunk_table_size += 8;
         self.chunk_table_size += 32;
-        println!("{:70} {:20}", utils::bytes_to_hex(chunk_id.to_vec()), start-self.lastOffset);
-        chunkItems.push(ChunkData{
+        println!("{:70} {:20}", utils::bytes_to_hex(chunk_id.to_vec()), start-self.last_offset);
+        chunk_items.push(ChunkData{
                         id: chunk_id,
-                        start: start,
-                        size: start - self.lastOffset
+                        start,
+                        size: start - self.last_offset
                     });
-        self.chunk_data = chunkItems;
+        self.chunk_data = chunk_items;
     }
     fn write_tail(&mut self) {
 
@@ -224,9 +316,15 @@ impl Index for InMemoryIndex {
     fn read(&mut self) {
 
     }
-    fn getChunkData(&self) -> Vec<ChunkData> {
+    fn get_chunk_data(&self) -> Vec<ChunkData> {
         self.chunk_data.clone()
     }
+    fn digest(&self) -> ChunkDigest {
+        ChunkDigest::default()
+    }
+    fn chunk_sizes(&self) -> (u64, u64, u64) {
+        (0, 0, 0)
+    }
 }
 
 use std::clone::Clone;
@@ -242,4 +340,81 @@ pub struct ChunkData {
     pub id: [u8;32],
     pub start: u64,
     pub size: u64
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_http_urls_are_remote() {
+        assert!(is_remote("http://example.com/blob.caibx"));
+        assert!(is_remote("https://example.com/blob.caibx"));
+        assert!(!is_remote("foo:bar.caibx"));
+        assert!(!is_remote("c:blob.caibx"));
+        assert!(!is_remote("ftp://example.com/blob.caibx"));
+        assert!(!is_remote("/tmp/blob.caibx"));
+        assert!(!is_remote("blob.caibx"));
+    }
+
+    #[test]
+    fn open_index_reads_local_path_with_colon() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("foo:bar.caibx");
+        let path = path.to_str().unwrap();
+        let mut index = LocalIndexFile::new(path);
+        index.write_header(16, 256, 64, ChunkDigest::SHA256);
+        index.add_entry(100, [1;32]);
+        index.add_entry(250, [2;32]);
+        index.write_tail();
+
+        let mut opened = open_index(path, &store::StoreOptions::default());
+        opened.read();
+        let chunks = opened.get_chunk_data();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!((chunks[1].start, chunks[1].size, chunks[1].id), (100, 150, [2;32]));
+        assert_eq!(opened.digest(), ChunkDigest::SHA256);
+    }
+
+    #[test]
+    fn empty_chunk_entries_of_older_indexes_are_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.caibx");
+        let path = path.to_str().unwrap();
+        // Empty input, and input ending on a boundary, as older versions chunked them
+        let mut index = LocalIndexFile::new(path);
+        index.write_header(16, 256, 64, ChunkDigest::default());
+        index.add_entry(0, [1;32]);
+        index.write_tail();
+        let mut opened = LocalIndexFile::open(path);
+        opened.read();
+        assert!(opened.get_chunk_data().is_empty());
+
+        let mut index = LocalIndexFile::new(path);
+        index.write_header(16, 256, 64, ChunkDigest::default());
+        index.add_entry(100, [1;32]);
+        index.add_entry(100, [2;32]);
+        index.write_tail();
+        let mut opened = LocalIndexFile::open(path);
+        opened.read();
+        let chunks = opened.get_chunk_data();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!((chunks[0].start, chunks[0].size, chunks[0].id), (0, 100, [1;32]));
+    }
+
+    #[test]
+    fn written_entries_are_kept_in_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("new.caibx");
+        let mut index = LocalIndexFile::new(path.to_str().unwrap());
+        index.write_header(16, 256, 64, ChunkDigest::default());
+        index.add_entry(100, [1;32]);
+        index.add_entry(250, [2;32]);
+        index.write_tail();
+        let written = index.get_chunk_data();
+        let mut opened = LocalIndexFile::open(path.to_str().unwrap());
+        opened.read();
+        let read = opened.get_chunk_data();
+        assert_eq!(written.iter().map(|c| (c.id, c.start, c.size)).collect::<Vec<_>>(), read.iter().map(|c| (c.id, c.start, c.size)).collect::<Vec<_>>());
+    }
+}
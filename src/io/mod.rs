@@ -1,19 +1,33 @@
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
 use std::rc::Rc;
-use std::io::Write;
+use std::io::{Write, Seek, SeekFrom};
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use log::{debug, info};
+
+use crate::digest::ChunkDigest;
+use crate::index;
+
+// _IOR(0x12, 114, size_t), size of a block device in bytes
+const BLKGETSIZE64: libc::c_ulong = 0x80081272;
 
 pub struct LocalSourceFile {
     pub path: String,
-    pub file: Rc<File>
+    pub file: Rc<File>,
+    // Bytes to chunk, taken from the device for block devices instead of reading to EOF
+    pub size: u64
 }
 
 impl LocalSourceFile {
     pub fn new(path: String) -> LocalSourceFile {
         match File::open(&path) {
             Ok(f) => {
+                let size = source_size(&f);
                 LocalSourceFile {
-                    path: path,
-                    file: Rc::new(f)
+                    path,
+                    file: Rc::new(f),
+                    size
                 }
             },
             Err(e) => {
@@ -21,20 +35,89 @@ impl LocalSourceFile {
             }
         }
     }
+
+    // Source already opened elsewhere, e.g. a temporary file
+    pub fn from_file(path: &str, file: File) -> LocalSourceFile {
+        let size = source_size(&file);
+        LocalSourceFile {
+            path: String::from(path),
+            file: Rc::new(file),
+            size
+        }
+    }
+}
+
+pub fn is_block_device(file: &File) -> bool {
+    file.metadata().map(|m| m.file_type().is_block_device()).unwrap_or(false)
+}
+
+// Leaves size bytes at offset of file as a hole, punching out data already there, returns the
+// bytes left sparse. Devices and filesystems without holes get the zeros written.
+pub fn write_zeros_at(file: &File, offset: u64, size: u64, is_device: bool) -> u64 {
+    if !is_device {
+        let len = file.metadata().expect("Error: Cannot read output size").len();
+        if offset >= len {
+            // Past the end, the hole is there once the file is resized
+            return size;
+        }
+        let punched = unsafe {
+            libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t, std::cmp::min(size, len - offset) as libc::off_t)
+        } == 0;
+        if punched {
+            return size;
+        }
+        debug!("Could not punch hole at {}, {:?}", offset, std::io::Error::last_os_error());
+    }
+    file.write_all_at(&vec![0; size as usize], offset).expect("Error: Cannot write zeros to output");
+    0
+}
+
+// Size of a block device as reported by the kernel
+pub fn block_device_size(file: &File) -> u64 {
+    let mut size: u64 = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64 as _, &mut size) } != 0 {
+        panic!("Could not get size of block device, {:?}", std::io::Error::last_os_error());
+    }
+    size
+}
+
+fn source_size(file: &File) -> u64 {
+    if is_block_device(file) {
+        let size = block_device_size(file);
+        info!("Block device of {} bytes", size);
+        size
+    } else {
+        file.metadata().expect("Error: Cannot read file size").len()
+    }
 }
 
 pub struct LocalOutputFile {
     pub path: String,
-    pub file: Rc<File>
+    pub file: Rc<File>,
+    // Set for block devices, which are written in place and never truncated
+    pub device_size: Option<u64>,
+    // Pipes and terminals, written strictly in order without seeking or reading back
+    pub stream: bool
 }
 
 impl LocalOutputFile {
     pub fn new(path: &str) -> LocalOutputFile {
-        match File::create(&path) {
+        let is_device = fs::metadata(path).map(|m| m.file_type().is_block_device()).unwrap_or(false);
+        let opened = if is_device {
+            OpenOptions::new().read(true).write(true).open(Path::new(path))
+        } else {
+            // Readable too, chunks written are checked against the index
+            OpenOptions::new().read(true).write(true).create(true).truncate(true).open(Path::new(path))
+        };
+        match opened {
             Ok(f) => {
+                let device_size = if is_device { Some(block_device_size(&f)) } else { None };
                 LocalOutputFile {
                     path: String::from(path),
-                    file: Rc::new(f)
+                    file: Rc::new(f),
+                    device_size,
+                    stream: false
                 }
             },
             Err(e) => {
@@ -42,8 +125,150 @@ impl LocalOutputFile {
             }
         }
     }
+    // Opens the output without truncating it, its current content is updated in place
+    pub fn open_in_place(path: &str) -> LocalOutputFile {
+        let is_device = fs::metadata(path).map(|m| m.file_type().is_block_device()).unwrap_or(false);
+        if is_device {
+            return LocalOutputFile::new(path);
+        }
+        match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(Path::new(path)) {
+            Ok(f) => {
+                LocalOutputFile {
+                    path: String::from(path),
+                    file: Rc::new(f),
+                    device_size: None,
+                    stream: false
+                }
+            },
+            Err(e) => {
+                panic!("Could not open file, {:?}",e);
+            }
+        }
+    }
+    pub fn from_file(path: &str, file: File) -> LocalOutputFile {
+        LocalOutputFile {
+            path: String::from(path),
+            file: Rc::new(file),
+            device_size: None,
+            stream: false
+        }
+    }
+    // Standard output, for piping the assembled blob into another program
+    pub fn stdout() -> LocalOutputFile {
+        // A copy of the descriptor, dropping the output must not close stdout itself
+        let fd = unsafe { libc::dup(libc::STDOUT_FILENO) };
+        if fd < 0 {
+            panic!("Could not open stdout, {:?}", std::io::Error::last_os_error());
+        }
+        LocalOutputFile {
+            path: String::from("-"),
+            file: Rc::new(unsafe { File::from_raw_fd(fd) }),
+            device_size: None,
+            stream: true
+        }
+    }
     pub fn write_all(&mut self, buf: Vec<u8>) {
-        let mut output_file = Rc::get_mut(&mut self.file).unwrap();
-        output_file.write_all(&buf);
+        let output_file = Rc::get_mut(&mut self.file).unwrap();
+        if let Err(e) = output_file.write_all(&buf) {
+            panic!("Could not write to {}, {:?}", self.path, e);
+        }
+    }
+
+    // Whether the output already holds the chunk at its offset
+    pub fn matches(&self, start: u64, size: u64, id: &[u8;32], digest: ChunkDigest) -> bool {
+        let mut buf = vec![0; size as usize];
+        match self.file.read_exact_at(&mut buf, start) {
+            Ok(()) => digest.sum(&buf) == *id,
+            Err(_) => false
+        }
+    }
+
+    // Verifies the chunks a previous extract left in the output in order, returns the index of
+    // the first one missing or damaged and moves the output there
+    pub fn resume_point(&mut self, chunks: &[index::ChunkData], digest: ChunkDigest) -> usize {
+        let first = chunks.iter().position(|c| !self.matches(c.start, c.size, &c.id, digest)).unwrap_or(chunks.len());
+        let start = chunks.get(first).map_or_else(|| chunks.last().map_or(0, |c| c.start + c.size), |c| c.start);
+        let output_file = Rc::get_mut(&mut self.file).unwrap();
+        output_file.seek(SeekFrom::Start(start)).expect("Error: Cannot seek in output");
+        first
+    }
+
+    // Leaves a chunk of zeros as a hole, returns the bytes left sparse
+    pub fn write_zeros(&mut self, size: u64) -> u64 {
+        let pos = {
+            let output_file = Rc::get_mut(&mut self.file).unwrap();
+            output_file.stream_position().expect("Error: Cannot seek in output")
+        };
+        let sparse = write_zeros_at(&self.file, pos, size, self.device_size.is_some());
+        self.skip(size);
+        sparse
+    }
+
+    // Moves past a chunk which is already in place
+    pub fn skip(&mut self, size: u64) {
+        let output_file = Rc::get_mut(&mut self.file).unwrap();
+        output_file.seek(SeekFrom::Current(size as i64)).expect("Error: Cannot seek in output");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::tests::random_bytes;
+
+    // Chunks of 1000 bytes each, the last one shorter
+    fn chunks_of(data: &[u8], digest: ChunkDigest) -> Vec<index::ChunkData> {
+        data.chunks(1000).enumerate().map(|(i, c)| index::ChunkData {
+            id: digest.sum(c),
+            start: i as u64 * 1000,
+            size: c.len() as u64
+        }).collect()
+    }
+
+    fn resume_point(path: &Path, chunks: &[index::ChunkData], digest: ChunkDigest) -> (usize, u64) {
+        let mut output = LocalOutputFile::open_in_place(path.to_str().unwrap());
+        let first = output.resume_point(chunks, digest);
+        let pos = Rc::get_mut(&mut output.file).unwrap().stream_position().unwrap();
+        (first, pos)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn resume_point_is_the_first_missing_or_damaged_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output");
+        let digest = ChunkDigest::default();
+        let data = random_bytes(10_500);
+        let chunks = chunks_of(&data, digest);
+
+        // Killed in the middle of writing the fifth chunk
+        fs::write(&path, &data[..4_321]).unwrap();
+        assert_eq!(resume_point(&path, &chunks, digest), (4, 4_000));
+
+        // A damaged chunk before chunks which look fine, everything after it is rewritten
+        let mut damaged = data.clone();
+        damaged[2_500] ^= 1;
+        fs::write(&path, &damaged).unwrap();
+        assert_eq!(resume_point(&path, &chunks, digest), (2, 2_000));
+
+        fs::write(&path, b"").unwrap();
+        assert_eq!(resume_point(&path, &chunks, digest), (0, 0));
+    }
+
+    #[test]
+    fn complete_output_resumes_at_its_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output");
+        let digest = ChunkDigest::default();
+        let data = random_bytes(10_500);
+        let chunks = chunks_of(&data, digest);
+        fs::write(&path, &data).unwrap();
+        assert_eq!(resume_point(&path, &chunks, digest), (chunks.len(), 10_500));
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn dropping_the_stdout_output_keeps_stdout_open() {
+        drop(LocalOutputFile::stdout());
+        assert!(unsafe { libc::fcntl(libc::STDOUT_FILENO, libc::F_GETFD) } >= 0);
+    }
+}
@@ -22,20 +22,35 @@ mod chunker;
 mod utils;
 mod assembler;
 mod seed;
+mod digest;
+mod catar;
+mod progress;
+mod stats;
+mod reader;
 
 extern crate log;
 extern crate log4rs;
 extern crate clap;
 extern crate hyper;
+extern crate hyper_tls;
+extern crate native_tls;
 extern crate url;
 extern crate rustc_serialize;
 extern crate bytes;
 extern crate tokio;
+extern crate tempfile;
+extern crate chrono;
+extern crate ssh2;
+extern crate rand;
+extern crate libc;
 
 use crate::assembler::AssembleOps;
 use crate::index::Index;
 use log::{info};
 use clap::ArgMatches;
+use std::io::{Read, Seek, SeekFrom};
+use std::rc::Rc;
+use std::time::Instant;
 
 fn main() {
     log4rs::init_file("log4rs.yml", Default::default()).unwrap();
@@ -48,17 +63,35 @@ fn main() {
             let index_file_name = sub_com.value_of("index").unwrap_or("index.caibx");
             let store_folder_name = sub_com.value_of("store").unwrap_or("default.castr");
             let input_file_name = sub_com.value_of("file").unwrap();
+            let store_options = store_options_from_cli(sub_com);
+            let digest = store_options.digest;
+            let concurrency = concurrency_from_cli(sub_com, store_folder_name);
+            let new_store = || store::get_suitable_store(store_folder_name, chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT, &store_options);
 
             // TODO: Should have been Chunker instead of ChunkerConfig, separate out configuration
-            let mut chunkerConfig = chunker::ChunkerConfig {
+            let mut chunker_config = chunker::ChunkerConfig {
                 index: Box::new(index::LocalIndexFile::new(index_file_name)),
-                store: Box::new(store::LocalStore::new(store_folder_name, chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT)),
+                store: new_store(),
+                worker_stores: (1..concurrency).map(|_| new_store()).collect(),
                 source: Box::new(io::LocalSourceFile::new(String::from(input_file_name))),
                 min_size: chunker::CHUNK_SIZE_MIN_DEFAULT,
                 max_size: chunker::CHUNK_SIZE_MAX_DEFAULT,
-                avg_size: chunker::CHUNK_SIZE_AVG_DEFAULT
+                avg_size: chunker::CHUNK_SIZE_AVG_DEFAULT,
+                digest,
+                progress: progress::for_terminal()
             };
-            chunkerConfig.chunk();
+            let started = Instant::now();
+            chunker_config.chunk();
+
+            let mut store_stats = store::StoreStats::new(chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT);
+            for s in std::iter::once(&chunker_config.store).chain(chunker_config.worker_stores.iter()) {
+                if let Some(worker_stats) = s.stats() {
+                    store_stats.add(worker_stats);
+                }
+            }
+            let mut report = stats::Report::new("make", &chunker_config.index.get_chunk_data(), started.elapsed());
+            report.add_store_stats(&store_stats);
+            report.print(stats_format_from_cli(sub_com), &mut std::io::stdout());
         },
         ("extract", Some(sub_com)) => {
             let index_file_name = sub_com.value_of("index").unwrap_or("index.caibx");
@@ -66,24 +99,69 @@ fn main() {
             let output_file_name = sub_com.value_of("file").unwrap();
             let seed_index_file = sub_com.value_of("seed-index");
             let seed_file = sub_com.value_of("seed-file");
+            let store_options = store::StoreOptions { read_only: true, ..store_options_from_cli(sub_com) };
+            let concurrency = concurrency_from_cli(sub_com, store_folder_name);
+            let new_store = || store::get_suitable_store(store_folder_name, chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT, &store_options);
+
+            let mut new_index = index::open_index(index_file_name, &store_options);
+            new_index.read();
+            // Archives are assembled into a temporary catar stream and unpacked into the output directory
+            // The blob goes to stdout as is, archives included, with everything else on stderr
+            let to_stdout = output_file_name == "-";
+            // A range is a piece of the blob, written out as is even for archives
+            let range = range_from_cli(sub_com);
+            let is_archive = new_index.is_archive() && !to_stdout && range.is_none();
+            let skip_matching = sub_com.is_present("skip-matching");
+            if range.is_some() && (sub_com.is_present("in-place") || sub_com.is_present("resume") || sub_com.is_present("verify")) {
+                info!("Ignoring in-place, resume and verify, a range is written to a fresh output");
+            }
+            let in_place = sub_com.is_present("in-place") && !is_archive && range.is_none();
+            if in_place && seed_file.is_some() {
+                info!("Ignoring seed, the output is its own seed in place");
+            }
+            let seed_file = if in_place { None } else { seed_file };
+            let resume = sub_com.is_present("resume") && !is_archive && range.is_none();
+            let output = if to_stdout {
+                io::LocalOutputFile::stdout()
+            } else if is_archive {
+                info!("{} is an archive index, unpacking into {}", index_file_name, output_file_name);
+                let archive = tempfile::tempfile().expect("Error: Cannot create temporary archive file");
+                io::LocalOutputFile::from_file(output_file_name, archive)
+            } else if in_place || resume {
+                io::LocalOutputFile::open_in_place(output_file_name)
+            } else {
+                io::LocalOutputFile::new(output_file_name)
+            };
 
+            // Counts where the chunks came from for the report
+            let (counter, counts) = progress::Counter::new(progress::for_terminal());
             let mut a = if let Some(seed_file_name) = seed_file {
                 if let Some(seed_index_file_name) = seed_index_file {
                         assembler::AssemblerConfig {
                             seed: Some(seed::LocalSeedFile::new(seed_file_name)),
-                            seed_index: Some(Box::new(index::LocalIndexFile::open(seed_index_file_name))),
-                            store: store::get_suitable_store(store_folder_name, chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT),
-                            new_index: Box::new(index::LocalIndexFile::open(index_file_name)),
-                            output: Box::new(io::LocalOutputFile::new(output_file_name))
+                            seed_index: Some(Box::new(index::open_index(seed_index_file_name, &store_options))),
+                            store: new_store(),
+                            worker_stores: (1..concurrency).map(|_| new_store()).collect(),
+                            new_index: Box::new(new_index),
+                            output: Box::new(output),
+                            skip_matching,
+                            in_place,
+                            resume,
+                            progress: Box::new(counter)
                         } 
                     } else {
                         info!("Ignoring seed, seed_index");        
                         assembler::AssemblerConfig {
                             seed: None,
                             seed_index: None,
-                            store: store::get_suitable_store(store_folder_name, chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT),
-                            new_index: Box::new(index::LocalIndexFile::open(index_file_name)),
-                            output: Box::new(io::LocalOutputFile::new(output_file_name))
+                            store: new_store(),
+                            worker_stores: (1..concurrency).map(|_| new_store()).collect(),
+                            new_index: Box::new(new_index),
+                            output: Box::new(output),
+                            skip_matching,
+                            in_place,
+                            resume,
+                            progress: Box::new(counter)
                         }
                     }
             } else {
@@ -91,41 +169,371 @@ fn main() {
                 assembler::AssemblerConfig {
                     seed: None,
                     seed_index: None,
-                    store: store::get_suitable_store(store_folder_name, chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT),
-                    new_index: Box::new(index::LocalIndexFile::open(index_file_name)),
-                    output: Box::new(io::LocalOutputFile::new(output_file_name))
+                    store: new_store(),
+                    worker_stores: (1..concurrency).map(|_| new_store()).collect(),
+                    new_index: Box::new(new_index),
+                    output: Box::new(output),
+                    skip_matching,
+                    in_place,
+                    resume,
+                    progress: Box::new(counter)
                 }
             };
-            a.assemble();
+            let started = Instant::now();
+            let mut report = match range {
+                Some((offset, length)) => {
+                    let file = a.output.file.clone();
+                    a.assemble_range(offset, length, &mut &*file);
+                    let chunks = a.new_index.get_chunk_data();
+                    stats::Report::new("extract", assembler::chunks_in_range(&chunks, offset, length), started.elapsed())
+                },
+                None => {
+                    a.assemble();
+                    stats::Report::new("extract", &a.new_index.get_chunk_data(), started.elapsed())
+                }
+            };
+            report.add_source_counts(&counts.lock().unwrap());
+            let verify = sub_com.is_present("verify") && range.is_none();
+            if verify && to_stdout {
+                info!("Cannot verify output written to stdout");
+            } else if verify {
+                let chunks = a.new_index.get_chunk_data();
+                let digest = a.new_index.digest();
+                let is_device = a.output.device_size.is_some();
+                let repair_store = if sub_com.is_present("repair") { Some(&mut a.store) } else { None };
+                if !verify_output(&a.output.file, &chunks, digest, concurrency, repair_store, is_device) {
+                    std::process::exit(1);
+                }
+            }
+            if is_archive {
+                let entries = unpack_archive(&mut a.output, output_file_name);
+                println!("Restored {} entries into {}", entries, output_file_name);
+            }
+            if to_stdout {
+                report.print(stats_format_from_cli(sub_com), &mut std::io::stderr());
+            } else {
+                report.print(stats_format_from_cli(sub_com), &mut std::io::stdout());
+            }
+        },
+        ("cat", Some(sub_com)) => {
+            let index_file_name = sub_com.value_of("index").unwrap();
+            let store_folder_name = sub_com.value_of("store").unwrap_or("default.castr");
+            let store_options = store::StoreOptions { read_only: true, ..store_options_from_cli(sub_com) };
+            let cache_chunks = match sub_com.value_of("cache-size") {
+                Some(c) => c.parse::<usize>().ok().filter(|c| *c > 0).expect("Error: cache-size must be a positive number"),
+                None => reader::CACHE_CHUNKS_DEFAULT
+            };
+            let (offset, length) = range_from_cli(sub_com).unwrap_or((0, None));
+
+            let new_index = index::open_index(index_file_name, &store_options);
+            let store = store::get_suitable_store(store_folder_name, chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT, &store_options);
+            let mut blob = reader::IndexReader::new(Box::new(new_index), store, cache_chunks);
+            if offset > blob.size() {
+                panic!("Range starts at {}, past the end of the {} byte blob", offset, blob.size());
+            }
+            blob.seek(SeekFrom::Start(offset)).unwrap();
+            let mut range = blob.take(length.unwrap_or(u64::MAX));
+            let stdout = std::io::stdout();
+            match std::io::copy(&mut range, &mut stdout.lock()) {
+                Ok(written) => info!("Printed {} bytes", written),
+                // Whoever reads the output has all they want
+                Err(ref e) if e.kind() == std::io::ErrorKind::BrokenPipe => {},
+                Err(e) => panic!("Could not print blob, {:?}", e)
+            }
+        },
+        ("verify", Some(sub_com)) => {
+            let index_file_name = sub_com.value_of("index").unwrap();
+            let output_file_name = sub_com.value_of("file").unwrap();
+            let store_folder_name = sub_com.value_of("store").unwrap_or("default.castr");
+            let store_options = store::StoreOptions { read_only: true, ..store_options_from_cli(sub_com) };
+            let concurrency = concurrency_from_cli(sub_com, store_folder_name);
+            let repair = sub_com.is_present("repair");
+
+            let mut index_file = index::open_index(index_file_name, &store_options);
+            index_file.read();
+            let file = match std::fs::OpenOptions::new().read(true).write(repair).open(output_file_name) {
+                Ok(f) => f,
+                Err(e) => {
+                    panic!("Could not open {}, {:?}", output_file_name, e);
+                }
+            };
+            let is_device = io::is_block_device(&file);
+            let mut store = if repair {
+                Some(store::get_suitable_store(store_folder_name, chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT, &store_options))
+            } else {
+                None
+            };
+            if !verify_output(&file, &index_file.get_chunk_data(), index_file.digest(), concurrency, store.as_mut(), is_device) {
+                std::process::exit(1);
+            }
         },
         ("list-chunks", Some(sub_com)) => {
             let index_file = sub_com.value_of("index");
             let input_file = sub_com.value_of("file");
             if let Some(index_file_name) = index_file {
-                let mut index_holder = index::LocalIndexFile::open(index_file_name);
+                let mut index_holder = index::open_index(index_file_name, &store_options_from_cli(sub_com));
                 index_holder.read();
-                println!("\nTotal number of chunks {}\n", index_holder.getChunkData().len());
+                println!("\nTotal number of chunks {}\n", index_holder.get_chunk_data().len());
                 println!("chunk_id/start/size(bytes):\n");
-                index_holder.getChunkData().iter().for_each(|chunk| {
+                index_holder.get_chunk_data().iter().for_each(|chunk| {
                     println!("{:70} {:20} {:20}", utils::bytes_to_hex(chunk.id.to_vec()), chunk.start, chunk.size);
                 });
                 println!("Done!");
             } else if let Some(input_file_name) = input_file {
-                let mut chunkerConfig = chunker::ChunkerConfig {
+                let digest = store_options_from_cli(sub_com).digest;
+                let mut dummy_store = store::DummyStore::new(chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT);
+                dummy_store.digest = digest;
+                let mut chunker_config = chunker::ChunkerConfig {
                     index: Box::new(index::InMemoryIndex::new("")),
-                    store: Box::new(store::DummyStore::new(chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT)),
+                    store: Box::new(dummy_store),
+                    worker_stores: Vec::new(),
                     source: Box::new(io::LocalSourceFile::new(String::from(input_file_name))),
                     min_size: chunker::CHUNK_SIZE_MIN_DEFAULT,
                     max_size: chunker::CHUNK_SIZE_MAX_DEFAULT,
-                    avg_size: chunker::CHUNK_SIZE_AVG_DEFAULT
+                    avg_size: chunker::CHUNK_SIZE_AVG_DEFAULT,
+                    digest,
+                    progress: Box::new(progress::NoProgress)
                 };
-                chunkerConfig.chunk();
+                chunker_config.chunk();
             } else {
                 panic!("invalid options");
             }
         },
+        ("verify-index", Some(sub_com)) => {
+            let index_file_name = sub_com.value_of("index").unwrap();
+            let input_file_name = sub_com.value_of("f").unwrap();
+            let mut index_holder = index::open_index(index_file_name, &store_options_from_cli(sub_com));
+            index_holder.read();
+            let chunks = index_holder.get_chunk_data();
+            // Chunked the way the index was made, with its chunk sizes and digest
+            let (min_size, avg_size, max_size) = index_holder.chunk_sizes();
+            let source = io::LocalSourceFile::new(String::from(input_file_name));
+            let found = chunker::find_chunk_ids(&source.file, source.size, min_size, max_size, avg_size, index_holder.digest());
+            match chunks.iter().zip(found.iter()).position(|(c, f)| c.id != f.id || c.start != f.start || c.size != f.size) {
+                Some(n) => {
+                    println!("Chunk {} at offset {} of {} does not match {}", n, chunks[n].start, input_file_name, index_file_name);
+                    std::process::exit(1);
+                },
+                None if chunks.len() != found.len() => {
+                    println!("{} has {} chunks, {} has {}", index_file_name, chunks.len(), input_file_name, found.len());
+                    std::process::exit(1);
+                },
+                None => {
+                    println!("{} matches {}, {} chunks", index_file_name, input_file_name, chunks.len());
+                }
+            }
+        },
+        ("tar", Some(sub_com)) => {
+            let index_file_name = sub_com.value_of("index").unwrap_or("index.caidx");
+            let store_folder_name = sub_com.value_of("store").unwrap_or("default.castr");
+            let dir_name = sub_com.value_of("dir").unwrap();
+            let store_options = store::StoreOptions { read_only: true, ..store_options_from_cli(sub_com) };
+            let digest = store_options.digest;
+            let concurrency = concurrency_from_cli(sub_com, store_folder_name);
+            let new_store = || store::get_suitable_store(store_folder_name, chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT, &store_options);
+
+            // The archive is serialized into a temporary file first and chunked like a blob
+            let mut archive = tempfile::tempfile().expect("Error: Cannot create temporary archive file");
+            let feature_flags = catar::ARCHIVE_FEATURE_FLAGS | digest.feature_flags();
+            let archive_size = catar::encode_tree(std::path::Path::new(dir_name), std::io::BufWriter::new(&mut archive), feature_flags);
+            archive.seek(SeekFrom::Start(0)).unwrap();
+            info!("Archived {} into {} bytes", dir_name, archive_size);
+
+            let mut index_file = index::LocalIndexFile::new(index_file_name);
+            index_file.feature_flags = catar::ARCHIVE_FEATURE_FLAGS;
+            let mut chunker_config = chunker::ChunkerConfig {
+                index: Box::new(index_file),
+                store: new_store(),
+                worker_stores: (1..concurrency).map(|_| new_store()).collect(),
+                source: Box::new(io::LocalSourceFile::from_file(dir_name, archive)),
+                min_size: chunker::CHUNK_SIZE_MIN_DEFAULT,
+                max_size: chunker::CHUNK_SIZE_MAX_DEFAULT,
+                avg_size: chunker::CHUNK_SIZE_AVG_DEFAULT,
+                digest,
+                progress: progress::for_terminal()
+            };
+            let started = Instant::now();
+            chunker_config.chunk();
+
+            let mut store_stats = store::StoreStats::new(chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT);
+            for s in std::iter::once(&chunker_config.store).chain(chunker_config.worker_stores.iter()) {
+                if let Some(worker_stats) = s.stats() {
+                    store_stats.add(worker_stats);
+                }
+            }
+            let mut report = stats::Report::new("tar", &chunker_config.index.get_chunk_data(), started.elapsed());
+            report.add_store_stats(&store_stats);
+            report.print(stats_format_from_cli(sub_com), &mut std::io::stdout());
+        },
+        ("untar", Some(sub_com)) => {
+            let index_file_name = sub_com.value_of("index").unwrap();
+            let store_folder_name = sub_com.value_of("store").unwrap_or("default.castr");
+            let dir_name = sub_com.value_of("dir").unwrap();
+            let store_options = store_options_from_cli(sub_com);
+
+            // The archive is assembled into a temporary file and unpacked from there
+            let archive = tempfile::tempfile().expect("Error: Cannot create temporary archive file");
+            let mut a = assembler::AssemblerConfig {
+                seed: None,
+                seed_index: None,
+                store: store::get_suitable_store(store_folder_name, chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT, &store_options),
+                worker_stores: Vec::new(),
+                new_index: Box::new(index::open_index(index_file_name, &store_options)),
+                output: Box::new(io::LocalOutputFile::from_file(dir_name, archive)),
+                skip_matching: false,
+                in_place: false,
+                resume: false,
+                progress: progress::for_terminal()
+            };
+            a.assemble();
+            let entries = unpack_archive(&mut a.output, dir_name);
+            println!("Restored {} entries into {}", entries, dir_name);
+        },
+        ("convert-store", Some(sub_com)) => {
+            let from = sub_com.value_of("from").unwrap();
+            let to = sub_com.value_of("to").unwrap();
+            store::convert_store(from, to, &store_options_from_cli(sub_com));
+            println!("Done!");
+        },
+        ("train-dictionary", Some(sub_com)) => {
+            let store_folder_name = sub_com.value_of("store").unwrap();
+            let samples = sub_com.value_of("samples").map_or(store::DICTIONARY_SAMPLES_DEFAULT, |s| {
+                s.parse::<usize>().expect("Error: samples must be a number")
+            });
+            let dictionary_size = sub_com.value_of("dictionary-size").map_or(store::DICTIONARY_SIZE_DEFAULT, |s| {
+                s.parse::<usize>().expect("Error: dictionary size must be a number")
+            });
+            let store_options = store_options_from_cli(sub_com);
+            let mut local_store = store::LocalStore::new(store_folder_name, chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT);
+            local_store.compression = store_options.compression();
+            local_store.encryption = store_options.encryption();
+            local_store.check_encryption(false);
+            let stats = store::train_dictionary(&mut local_store, samples, dictionary_size);
+            println!("Recompressed {} of {} chunks", stats.recompressed_chunks, stats.chunks);
+            println!("Store size before: {} bytes", stats.size_before);
+            println!("Store size after:  {} bytes ({:.1}%)", stats.size_after, 100.0 * stats.size_after as f64 / std::cmp::max(1, stats.size_before) as f64);
+        },
+        ("clean-store", Some(sub_com)) => {
+            let store_folder_name = sub_com.value_of("store").unwrap();
+            let local_store = store::LocalStore::new(store_folder_name, chunker::CHUNK_SIZE_MIN_DEFAULT, chunker::CHUNK_SIZE_MAX_DEFAULT, chunker::CHUNK_SIZE_AVG_DEFAULT);
+            let removed = local_store.remove_temp_files();
+            println!("Removed {} temporary files", removed);
+        },
         _ => {
             panic!("Arg not supported/provided");
         }
     };
 }
+
+// Unpacks the catar stream assembled into output, returns the number of restored entries
+fn unpack_archive(output: &mut io::LocalOutputFile, dir_name: &str) -> u64 {
+    let archive = Rc::get_mut(&mut output.file).unwrap();
+    archive.seek(SeekFrom::Start(0)).unwrap();
+    catar::decode_tree(std::io::BufReader::new(archive), std::path::Path::new(dir_name))
+}
+
+// Checks the output against the chunks of its index and prints every bad range, returns
+// whether the output is (now) good. Bad chunks are written again when given a store.
+fn verify_output(file: &std::fs::File, chunks: &[index::ChunkData], digest: digest::ChunkDigest, concurrency: usize, store: Option<&mut Box<dyn store::Store>>, is_device: bool) -> bool {
+    let total_size = chunks.last().map_or(0, |c| c.start + c.size);
+    let bad = assembler::find_bad_chunks(file, chunks, digest, concurrency);
+    for c in bad.iter() {
+        println!("Bad chunk {} at {}, {} bytes", utils::bytes_to_hex(c.id.to_vec()), c.start, c.size);
+    }
+    let size = file.metadata().expect("Error: Cannot read output size").len();
+    let wrong_size = !is_device && size != total_size;
+    if wrong_size {
+        println!("Output has {} bytes, index describes {}", size, total_size);
+    }
+    if bad.is_empty() && !wrong_size {
+        println!("Verified {} chunks", chunks.len());
+        return true;
+    }
+    match store {
+        Some(store) => {
+            assembler::repair(file, &bad, digest, store);
+            if wrong_size {
+                file.set_len(total_size).expect("Error: Cannot resize output");
+            }
+            // Checked again, a write may have failed or the store served the wrong data
+            let still_bad = assembler::find_bad_chunks(file, &bad, digest, concurrency);
+            if !still_bad.is_empty() {
+                println!("{} of {} repaired chunks are still bad", still_bad.len(), bad.len());
+                return false;
+            }
+            println!("Repaired {} of {} chunks", bad.len(), chunks.len());
+            true
+        },
+        None => {
+            println!("{} of {} chunks are bad", bad.len(), chunks.len());
+            false
+        }
+    }
+}
+
+fn stats_format_from_cli(sub_com: &ArgMatches) -> stats::StatsFormat {
+    stats::StatsFormat::from_name(sub_com.value_of("stats-format").unwrap_or("text"))
+}
+
+// Offset and optional length of the part of the blob to extract, None for all of it
+fn range_from_cli(sub_com: &ArgMatches) -> Option<(u64, Option<u64>)> {
+    let parse = |name| sub_com.value_of(name).map(|v: &str| v.parse::<u64>().unwrap_or_else(|_| panic!("Error: {} must be a number of bytes", name)));
+    let offset = parse("offset");
+    let length = parse("length");
+    if offset.is_none() && length.is_none() {
+        return None;
+    }
+    Some((offset.unwrap_or(0), length))
+}
+
+// Number of chunk workers, one per CPU unless given
+fn concurrency_from_cli(sub_com: &ArgMatches, store_folder_name: &str) -> usize {
+    let concurrency = match sub_com.value_of("concurrency") {
+        Some(c) => c.parse::<usize>().ok().filter(|c| *c > 0).expect("Error: concurrency must be a positive number"),
+        None => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    };
+    if store::is_pack_path(store_folder_name) && concurrency > 1 {
+        info!("Pack stores are written by a single worker");
+        return 1;
+    }
+    concurrency
+}
+
+fn store_options_from_cli(sub_com: &ArgMatches) -> store::StoreOptions {
+    store::StoreOptions {
+        ca_cert: sub_com.value_of("ca-cert").map(String::from),
+        client_cert: sub_com.value_of("client-cert").map(String::from),
+        client_key: sub_com.value_of("client-key").map(String::from),
+        insecure_skip_verify: sub_com.is_present("insecure-skip-verify"),
+        auth: match utils::read_secret(sub_com.value_of("basic-auth-env"), sub_com.value_of("basic-auth-file")) {
+            Some(credentials) => Some(store::HTTPAuth::basic_from_str(&credentials)),
+            None => utils::read_secret(sub_com.value_of("bearer-token-env"), sub_com.value_of("bearer-token-file")).map(store::HTTPAuth::Bearer)
+        },
+        headers: sub_com.values_of("header").map(|headers| {
+            headers.map(|h| {
+                match h.find(':') {
+                    Some(i) => (String::from(h[..i].trim()), String::from(h[i+1..].trim())),
+                    None => {
+                        panic!("Header {} must be of the form \"Name: value\"", h);
+                    }
+                }
+            }).collect()
+        }).unwrap_or_default(),
+        s3_endpoint: sub_com.value_of("s3-endpoint").map(String::from),
+        s3_region: sub_com.value_of("s3-region").map(String::from),
+        s3_credentials_file: sub_com.value_of("s3-credentials-file").map(String::from),
+        ssh_key: sub_com.value_of("ssh-key").map(String::from),
+        compression_level: sub_com.value_of("compression-level").map(|level| {
+            match level.parse::<i32>() {
+                Ok(l) => l,
+                Err(_) => {
+                    panic!("Invalid compression level {}", level);
+                }
+            }
+        }),
+        uncompressed: sub_com.is_present("uncompressed"),
+        encryption_key: sub_com.value_of("encryption-key").map(String::from),
+        digest: sub_com.value_of("digest").map_or(digest::ChunkDigest::default(), digest::ChunkDigest::from_name),
+        ..store::StoreOptions::default()
+    }
+}
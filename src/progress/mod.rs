@@ -0,0 +1,212 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::seed::SeedCopy;
+
+// Where a chunk which was processed came from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChunkSource {
+    // Read from the input while making an index
+    Input,
+    Seed,
+    Store,
+    // Fetched from the store earlier in the same run and kept in memory
+    Cache,
+    // Already held by the output
+    Output,
+    // All zeros, nothing had to be fetched
+    Zero
+}
+
+// Receives progress of chunking and assembling, implementations are handed to ChunkerConfig
+// and AssemblerConfig
+pub trait Progress: Send {
+    // Called once before the first chunk with the bytes to be processed
+    fn start(&mut self, total_bytes: u64);
+    fn chunk_done(&mut self, size: u64, source: ChunkSource);
+    // Called for chunks from the seed, before chunk_done, with how they got into the output
+    fn seed_copied(&mut self, _size: u64, _how: SeedCopy) {}
+    fn finish(&mut self);
+}
+
+// Reports nothing
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn start(&mut self, _total_bytes: u64) {}
+    fn chunk_done(&mut self, _size: u64, _source: ChunkSource) {}
+    fn finish(&mut self) {}
+}
+
+// Progress bar for the terminal when stderr is one, nothing otherwise
+pub fn for_terminal() -> Box<dyn Progress> {
+    if unsafe { libc::isatty(libc::STDERR_FILENO) } == 1 {
+        Box::new(ProgressBar::new())
+    } else {
+        Box::new(NoProgress)
+    }
+}
+
+// Chunks and bytes processed from each source
+#[derive(Clone, Default)]
+pub struct SourceCounts {
+    pub chunks: u64,
+    pub input_bytes: u64,
+    pub seed_bytes: u64,
+    pub store_bytes: u64,
+    pub cache_bytes: u64,
+    pub output_bytes: u64,
+    pub zero_bytes: u64,
+    // Seed bytes by how they got into the output
+    pub cloned_bytes: u64,
+    pub copied_range_bytes: u64,
+    pub copied_bytes: u64
+}
+
+// Counts what passes through and hands it on to another Progress, the counts stay readable
+// after the chunker or assembler is done
+pub struct Counter {
+    inner: Box<dyn Progress>,
+    counts: Arc<Mutex<SourceCounts>>
+}
+
+impl Counter {
+    pub fn new(inner: Box<dyn Progress>) -> (Counter, Arc<Mutex<SourceCounts>>) {
+        let counts = Arc::new(Mutex::new(SourceCounts::default()));
+        (Counter { inner, counts: counts.clone() }, counts)
+    }
+}
+
+impl Progress for Counter {
+    fn start(&mut self, total_bytes: u64) {
+        self.inner.start(total_bytes);
+    }
+
+    fn chunk_done(&mut self, size: u64, source: ChunkSource) {
+        {
+            let mut counts = self.counts.lock().unwrap();
+            counts.chunks += 1;
+            match source {
+                ChunkSource::Input => counts.input_bytes += size,
+                ChunkSource::Seed => counts.seed_bytes += size,
+                ChunkSource::Store => counts.store_bytes += size,
+                ChunkSource::Cache => counts.cache_bytes += size,
+                ChunkSource::Output => counts.output_bytes += size,
+                ChunkSource::Zero => counts.zero_bytes += size
+            }
+        }
+        self.inner.chunk_done(size, source);
+    }
+
+    fn seed_copied(&mut self, size: u64, how: SeedCopy) {
+        {
+            let mut counts = self.counts.lock().unwrap();
+            match how {
+                SeedCopy::Cloned => counts.cloned_bytes += size,
+                SeedCopy::CopiedRange => counts.copied_range_bytes += size,
+                SeedCopy::Copied => counts.copied_bytes += size
+            }
+        }
+        self.inner.seed_copied(size, how);
+    }
+
+    fn finish(&mut self) {
+        self.inner.finish();
+    }
+}
+
+const BAR_WIDTH: usize = 30;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+// Single line bar with throughput, ETA and where chunks came from, redrawn in place
+pub struct ProgressBar {
+    total_bytes: u64,
+    done_bytes: u64,
+    chunks: u64,
+    from_seed: u64,
+    from_store: u64,
+    from_cache: u64,
+    started: Instant,
+    last_draw: Option<Instant>
+}
+
+impl ProgressBar {
+    pub fn new() -> ProgressBar {
+        ProgressBar {
+            total_bytes: 0,
+            done_bytes: 0,
+            chunks: 0,
+            from_seed: 0,
+            from_store: 0,
+            from_cache: 0,
+            started: Instant::now(),
+            last_draw: None
+        }
+    }
+
+    fn draw(&mut self) {
+        self.last_draw = Some(Instant::now());
+        let fraction = if self.total_bytes == 0 { 1.0 } else { self.done_bytes as f64 / self.total_bytes as f64 };
+        let filled = std::cmp::min(BAR_WIDTH, (fraction * BAR_WIDTH as f64) as usize);
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { self.done_bytes as f64 / elapsed } else { 0.0 };
+        let eta = if rate > 0.0 { format_duration((self.total_bytes.saturating_sub(self.done_bytes)) as f64 / rate) } else { String::from("--:--") };
+        let mut line = format!("\r[{}{}] {:3.0}% {:>10}/s ETA {} {} chunks",
+            "#".repeat(filled), ".".repeat(BAR_WIDTH - filled), fraction * 100.0, format_bytes(rate as u64), eta, self.chunks);
+        if self.from_seed > 0 || self.from_store > 0 || self.from_cache > 0 {
+            line.push_str(&format!(" (seed {}, store {}, cache {})", self.from_seed, self.from_store, self.from_cache));
+        }
+        // Stdout may carry the stats report or the blob itself
+        let mut out = std::io::stderr();
+        // The terminal going away is no reason to stop working
+        let _ = out.write_all(line.as_bytes()).and_then(|_| out.flush());
+    }
+}
+
+impl Progress for ProgressBar {
+    fn start(&mut self, total_bytes: u64) {
+        self.total_bytes = total_bytes;
+        self.started = Instant::now();
+        self.draw();
+    }
+
+    fn chunk_done(&mut self, size: u64, source: ChunkSource) {
+        self.done_bytes += size;
+        self.chunks += 1;
+        match source {
+            ChunkSource::Seed => self.from_seed += 1,
+            ChunkSource::Store => self.from_store += 1,
+            ChunkSource::Cache => self.from_cache += 1,
+            _ => {}
+        }
+        if self.last_draw.is_none_or(|t| t.elapsed() >= REDRAW_INTERVAL) {
+            self.draw();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.draw();
+        eprintln!();
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs as u64;
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+
+use crate::digest::ChunkDigest;
+use crate::index;
+use crate::store;
+use crate::utils;
+
+// Chunks kept decompressed when no cache size is given
+pub const CACHE_CHUNKS_DEFAULT: usize = 16;
+
+// Reads the blob of an index from its chunks in a store as if it were a file. Chunks are only
+// fetched once read and the most recently used are kept, so reading in order or around the
+// same place fetches each chunk once.
+pub struct IndexReader {
+    chunks: Vec<index::ChunkData>,
+    digest: ChunkDigest,
+    store: Box<dyn store::Store>,
+    size: u64,
+    position: u64,
+    cache: ChunkCache
+}
+
+impl IndexReader {
+    pub fn new(mut new_index: Box<dyn index::Index>, store: Box<dyn store::Store>, cache_chunks: usize) -> IndexReader {
+        new_index.read();
+        let chunks = new_index.get_chunk_data();
+        let size = chunks.last().map_or(0, |c| c.start + c.size);
+        IndexReader {
+            chunks,
+            digest: new_index.digest(),
+            store,
+            size,
+            position: 0,
+            cache: ChunkCache::new(cache_chunks)
+        }
+    }
+
+    // Size of the blob in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn chunk_bytes(&mut self, n: usize) -> std::io::Result<&[u8]> {
+        let c = &self.chunks[n];
+        if !self.cache.contains(&c.id) {
+            let chunk_bytes = self.store.read_item(c.id.to_vec());
+            if chunk_bytes.len() as u64 != c.size || self.digest.sum(&chunk_bytes) != c.id {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Chunk {} from store does not match its id", utils::bytes_to_hex(c.id.to_vec()))));
+            }
+            self.cache.insert(c.id, chunk_bytes);
+        }
+        Ok(self.cache.get(&c.id).unwrap())
+    }
+}
+
+impl Read for IndexReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() || self.position >= self.size {
+            return Ok(0);
+        }
+        let position = self.position;
+        let n = self.chunks.partition_point(|c| c.start + c.size <= position);
+        let start = self.chunks[n].start;
+        let chunk_bytes = self.chunk_bytes(n)?;
+        let from = (position - start) as usize;
+        let count = std::cmp::min(buf.len(), chunk_bytes.len() - from);
+        buf[..count].copy_from_slice(&chunk_bytes[from..from + count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for IndexReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.size.checked_add_signed(d),
+            SeekFrom::Current(d) => self.position.checked_add_signed(d)
+        };
+        match position {
+            Some(p) => {
+                // Like files, reads past the end return nothing
+                self.position = p;
+                Ok(p)
+            },
+            None => Err(Error::new(ErrorKind::InvalidInput, "Seek to a negative or overflowing position"))
+        }
+    }
+}
+
+// Decompressed chunks by id, dropping the least recently used one when full
+pub struct ChunkCache {
+    capacity: usize,
+    chunks: HashMap<[u8;32], Vec<u8>>,
+    // Least recently used first
+    order: VecDeque<[u8;32]>
+}
+
+impl ChunkCache {
+    pub fn new(capacity: usize) -> ChunkCache {
+        ChunkCache { capacity: std::cmp::max(1, capacity), chunks: HashMap::new(), order: VecDeque::new() }
+    }
+
+    pub fn contains(&self, id: &[u8;32]) -> bool {
+        self.chunks.contains_key(id)
+    }
+
+    pub fn get(&mut self, id: &[u8;32]) -> Option<&Vec<u8>> {
+        if let Some(i) = self.order.iter().position(|o| o == id) {
+            let id = self.order.remove(i).unwrap();
+            self.order.push_back(id);
+        }
+        self.chunks.get(id)
+    }
+
+    pub fn insert(&mut self, id: [u8;32], chunk_bytes: Vec<u8>) {
+        if self.chunks.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.chunks.remove(&oldest);
+            }
+        }
+        self.chunks.insert(id, chunk_bytes);
+        self.order.push_back(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use crate::chunker::tests::random_bytes;
+
+    // Serves chunks from memory and records which ones were read
+    struct MemoryStore {
+        chunks: HashMap<Vec<u8>, Vec<u8>>,
+        reads: Reads
+    }
+
+    impl store::Store for MemoryStore {
+        fn create(&self, path: &str) -> PathBuf {
+            PathBuf::from(path)
+        }
+        fn write_item(&mut self, bytes: Vec<u8>) -> [u8;32] {
+            let id = ChunkDigest::default().sum(&bytes);
+            self.chunks.insert(id.to_vec(), bytes);
+            id
+        }
+        fn read_item(&mut self, id: Vec<u8>) -> Vec<u8> {
+            let mut read = [0; 32];
+            read.copy_from_slice(&id);
+            self.reads.lock().unwrap().push(read);
+            self.chunks[&id].clone()
+        }
+    }
+
+    // Ids of the chunks the store was asked for, in order
+    type Reads = Arc<Mutex<Vec<[u8;32]>>>;
+
+    // Reader of the blob made of parts, one chunk each, the chunk ids and the reads of its store
+    fn reader_of(parts: &[Vec<u8>], cache_chunks: usize) -> (IndexReader, Vec<[u8;32]>, Reads) {
+        let reads = Arc::new(Mutex::new(Vec::new()));
+        let mut store = MemoryStore { chunks: HashMap::new(), reads: reads.clone() };
+        let mut index = index::InMemoryIndex::new("");
+        let mut start = 0;
+        for part in parts.iter() {
+            let id = store::Store::write_item(&mut store, part.clone());
+            index.chunk_data.push(index::ChunkData { id, start, size: part.len() as u64 });
+            start += part.len() as u64;
+        }
+        let ids = index.chunk_data.iter().map(|c| c.id).collect();
+        (IndexReader::new(Box::new(index), Box::new(store), cache_chunks), ids, reads)
+    }
+
+    fn parts_of(data: &[u8]) -> Vec<Vec<u8>> {
+        data.chunks(1000).map(|c| c.to_vec()).collect()
+    }
+
+    fn read_n(reader: &mut IndexReader, n: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        reader.take(n as u64).read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn reads_and_seeks_like_a_file() {
+        let data = random_bytes(4_500);
+        let (mut reader, _, _) = reader_of(&parts_of(&data), CACHE_CHUNKS_DEFAULT);
+        assert_eq!(reader.size(), 4_500);
+        assert_eq!(read_n(&mut reader, 10_000), data);
+
+        assert_eq!(reader.seek(SeekFrom::Start(2_500)).unwrap(), 2_500);
+        // Across the boundary of two chunks
+        assert_eq!(read_n(&mut reader, 1_000), &data[2_500..3_500]);
+        assert_eq!(reader.seek(SeekFrom::Current(-600)).unwrap(), 2_900);
+        assert_eq!(read_n(&mut reader, 200), &data[2_900..3_100]);
+        assert_eq!(reader.seek(SeekFrom::End(-100)).unwrap(), 4_400);
+        assert_eq!(read_n(&mut reader, 1_000), &data[4_400..]);
+    }
+
+    #[test]
+    fn reads_past_the_end_return_nothing() {
+        let data = random_bytes(4_500);
+        let (mut reader, _, _) = reader_of(&parts_of(&data), CACHE_CHUNKS_DEFAULT);
+        assert_eq!(reader.seek(SeekFrom::End(10)).unwrap(), 4_510);
+        let mut buf = [0; 16];
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert_eq!(reader.seek(SeekFrom::Start(1_000_000)).unwrap(), 1_000_000);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn seeking_before_the_start_fails() {
+        let data = random_bytes(4_500);
+        let (mut reader, _, _) = reader_of(&parts_of(&data), CACHE_CHUNKS_DEFAULT);
+        reader.seek(SeekFrom::Start(100)).unwrap();
+        assert_eq!(reader.seek(SeekFrom::Current(-101)).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(reader.seek(SeekFrom::End(-4_501)).unwrap_err().kind(), ErrorKind::InvalidInput);
+        // The position is left alone
+        assert_eq!(read_n(&mut reader, 10), &data[100..110]);
+    }
+
+    #[test]
+    fn least_recently_used_chunk_is_dropped() {
+        let data = random_bytes(3_000);
+        let (mut reader, ids, reads) = reader_of(&parts_of(&data), 2);
+        for n in [0, 1, 0, 2, 0, 1].iter() {
+            reader.seek(SeekFrom::Start(*n * 1_000)).unwrap();
+            assert_eq!(read_n(&mut reader, 1), &data[*n as usize * 1_000..*n as usize * 1_000 + 1]);
+        }
+        // Reading chunk 2 dropped chunk 1, chunk 0 had been used since
+        assert_eq!(*reads.lock().unwrap(), vec![ids[0], ids[1], ids[2], ids[1]]);
+    }
+
+    #[test]
+    fn chunk_not_matching_its_id_is_invalid_data() {
+        let data = random_bytes(3_000);
+        let (mut reader, ids, _) = reader_of(&parts_of(&data), CACHE_CHUNKS_DEFAULT);
+        // The store hands out other bytes for the second chunk
+        reader.store = Box::new(MemoryStore {
+            chunks: ids.iter().enumerate().map(|(n, id)| (id.to_vec(), if n == 1 { vec![0; 1_000] } else { data[n * 1_000..(n + 1) * 1_000].to_vec() })).collect(),
+            reads: Arc::new(Mutex::new(Vec::new()))
+        });
+        assert_eq!(read_n(&mut reader, 1_000), &data[..1_000]);
+        let mut buf = [0; 16];
+        assert_eq!(reader.read(&mut buf).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
@@ -3,6 +3,31 @@ use std::io::SeekFrom;
 use std::io;
 use std::io::prelude::*;
 use std::rc::Rc;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use log::debug;
+
+
+// _IOW(0x94, 13, struct file_clone_range), shares extents between files on btrfs and XFS
+const FICLONERANGE: libc::c_ulong = 0x4020940d;
+
+#[repr(C)]
+struct FileCloneRange {
+    src_fd: i64,
+    src_offset: u64,
+    src_length: u64,
+    dest_offset: u64
+}
+
+// How a chunk got from the seed into the output
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SeedCopy {
+    // Blocks shared with the seed, nothing copied
+    Cloned,
+    // Copied by the kernel with copy_file_range
+    CopiedRange,
+    Copied
+}
 
 // Seed related data
 pub struct LocalSeedFile {
@@ -29,7 +54,56 @@ impl LocalSeedFile {
         let mut buf = Vec::new();
         let mut file = Rc::get_mut(&mut self.file).unwrap();
         file.seek(SeekFrom::Start(start)).unwrap();
-        io::copy(&mut std::io::Read::by_ref(&mut file).take(size), &mut buf);
+        if let Err(e) = io::copy(&mut std::io::Read::by_ref(&mut file).take(size), &mut buf) {
+            panic!("Could not read chunk from seed {}, {:?}", self.path, e);
+        }
         buf
     }
-}
\ No newline at end of file
+}
+
+// Copies size bytes at start of seed to offset dest of output, neither file position is used
+// so workers can share both files. Block aligned chunks are cloned when seed and output share a
+// filesystem with reflinks, others are copied by the kernel, reading into memory is the last
+// resort. Returns None when the seed is too short to hold them.
+pub fn copy_range(seed: &File, start: u64, size: u64, output: &File, dest: u64) -> Option<SeedCopy> {
+    let block_size = output.metadata().map(|m| m.blksize()).unwrap_or(0);
+    if block_size > 0 && start.is_multiple_of(block_size) && dest.is_multiple_of(block_size) && size.is_multiple_of(block_size) {
+        let range = FileCloneRange {
+            src_fd: seed.as_raw_fd() as i64,
+            src_offset: start,
+            src_length: size,
+            dest_offset: dest
+        };
+        if unsafe { libc::ioctl(output.as_raw_fd(), FICLONERANGE as _, &range) } == 0 {
+            return Some(SeedCopy::Cloned);
+        }
+        debug!("Could not clone seed range, {:?}", io::Error::last_os_error());
+    }
+
+    let mut copied = 0;
+    while copied < size {
+        let mut off_in = (start + copied) as libc::loff_t;
+        let mut off_out = (dest + copied) as libc::loff_t;
+        let n = unsafe {
+            libc::syscall(libc::SYS_copy_file_range, seed.as_raw_fd(), &mut off_in,
+                output.as_raw_fd(), &mut off_out, (size - copied) as libc::size_t, 0)
+        };
+        if n <= 0 {
+            if n < 0 {
+                debug!("Could not copy seed range, {:?}", io::Error::last_os_error());
+            }
+            break;
+        }
+        copied += n as u64;
+    }
+    if copied == size {
+        return Some(SeedCopy::CopiedRange);
+    }
+    let mut buf = vec![0; (size - copied) as usize];
+    if let Err(e) = seed.read_exact_at(&mut buf, start + copied) {
+        debug!("Could not read seed range, {:?}", e);
+        return None;
+    }
+    output.write_all_at(&buf, dest + copied).expect("Error: Cannot write chunk to output");
+    Some(SeedCopy::Copied)
+}
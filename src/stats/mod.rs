@@ -0,0 +1,147 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+use std::time::Duration;
+use rustc_serialize::json::Json;
+
+use crate::index::ChunkData;
+use crate::progress::SourceCounts;
+use crate::store::StoreStats;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum StatsFormat {
+    Text,
+    Json
+}
+
+impl StatsFormat {
+    pub fn from_name(name: &str) -> StatsFormat {
+        match name {
+            "text" => StatsFormat::Text,
+            "json" => StatsFormat::Json,
+            _ => {
+                panic!("Unknown stats format {}", name);
+            }
+        }
+    }
+}
+
+// A value of a report, keeps its JSON type whatever the run measured
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Count(u64),
+    // Ratios, seconds and rates
+    Fraction(f64)
+}
+
+// Summary of a make or extract run, printed once it is done
+pub struct Report {
+    operation: &'static str,
+    // Name and value in the order they are printed
+    values: Vec<(&'static str, Value)>
+}
+
+impl Report {
+    // Totals every report has, taken from the chunks of the index
+    pub fn new(operation: &'static str, chunks: &[ChunkData], elapsed: Duration) -> Report {
+        let total_bytes: u64 = chunks.iter().map(|c| c.size).sum();
+        let mut seen = HashSet::new();
+        let unique_bytes: u64 = chunks.iter().filter(|c| seen.insert(c.id)).map(|c| c.size).sum();
+        let seconds = elapsed.as_secs_f64();
+        let mut report = Report { operation, values: Vec::new() };
+        report.add("total_chunks", Value::Count(chunks.len() as u64));
+        report.add("unique_chunks", Value::Count(seen.len() as u64));
+        report.add("total_bytes", Value::Count(total_bytes));
+        report.add("unique_bytes", Value::Count(unique_bytes));
+        report.add("dedup_ratio", Value::Fraction(ratio(total_bytes, unique_bytes)));
+        report.add("elapsed_seconds", Value::Fraction(seconds));
+        report.add("throughput_bytes_per_second", Value::Fraction(if seconds > 0.0 { total_bytes as f64 / seconds } else { 0.0 }));
+        report
+    }
+
+    pub fn add(&mut self, name: &'static str, value: Value) {
+        self.values.push((name, value));
+    }
+
+    // Chunks the stores of make wrote or already had
+    pub fn add_store_stats(&mut self, stats: &StoreStats) {
+        self.add("new_chunks", Value::Count(stats.new_chunks_count));
+        self.add("existing_chunks", Value::Count(stats.count - stats.new_chunks_count));
+        self.add("new_bytes", Value::Count(stats.new_bytes));
+        self.add("stored_bytes", Value::Count(stats.stored_bytes));
+        self.add("compression_ratio", Value::Fraction(ratio(stats.new_bytes, stats.stored_bytes)));
+    }
+
+    // Where extract got the bytes of the output from
+    pub fn add_source_counts(&mut self, counts: &SourceCounts) {
+        self.add("bytes_from_seed", Value::Count(counts.seed_bytes));
+        self.add("seed_bytes_cloned", Value::Count(counts.cloned_bytes));
+        self.add("seed_bytes_copied_in_kernel", Value::Count(counts.copied_range_bytes));
+        self.add("seed_bytes_copied", Value::Count(counts.copied_bytes));
+        self.add("bytes_from_store", Value::Count(counts.store_bytes));
+        self.add("bytes_from_cache", Value::Count(counts.cache_bytes));
+        self.add("bytes_in_output", Value::Count(counts.output_bytes));
+        self.add("bytes_zero", Value::Count(counts.zero_bytes));
+    }
+
+    pub fn print(&self, format: StatsFormat, out: &mut dyn Write) {
+        let mut text = String::new();
+        match format {
+            StatsFormat::Text => {
+                text.push_str(&format!("{} stats:\n", self.operation));
+                for (name, value) in self.values.iter() {
+                    text.push_str(&format!("  {:30} {}\n", name.replace('_', " "), format_value(*value)));
+                }
+            },
+            StatsFormat::Json => {
+                let mut object = BTreeMap::new();
+                object.insert(String::from("operation"), Json::String(String::from(self.operation)));
+                for (name, value) in self.values.iter() {
+                    let json = match *value {
+                        Value::Count(n) => Json::U64(n),
+                        Value::Fraction(f) => Json::F64(f)
+                    };
+                    object.insert(String::from(*name), json);
+                }
+                text.push_str(&format!("{}\n", Json::Object(object)));
+            }
+        }
+        // A closed terminal or pipe is no reason to fail after the work is done
+        let _ = out.write_all(text.as_bytes());
+    }
+}
+
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 { 0.0 } else { a as f64 / b as f64 }
+}
+
+fn format_value(value: Value) -> String {
+    match value {
+        Value::Count(n) => format!("{}", n),
+        Value::Fraction(f) => format!("{:.3}", f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_of(report: &Report) -> Json {
+        let mut out = Vec::new();
+        report.print(StatsFormat::Json, &mut out);
+        Json::from_str(std::str::from_utf8(&out).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn fractions_stay_floats_in_json() {
+        let chunks = vec![ChunkData { id: [1;32], start: 0, size: 100 }, ChunkData { id: [1;32], start: 100, size: 100 }];
+        let mut report = Report::new("make", &chunks, Duration::from_secs(2));
+        report.add("compression_ratio", Value::Fraction(1.0));
+        let json = json_of(&report);
+        // Whole numbers this run, fractions in another
+        for name in ["dedup_ratio", "elapsed_seconds", "throughput_bytes_per_second", "compression_ratio"].iter() {
+            assert!(json.find(name).unwrap().is_f64(), "{} is {:?}", name, json.find(name));
+        }
+        assert_eq!(json.find("total_bytes").unwrap().as_u64(), Some(200));
+        assert_eq!(json.find("dedup_ratio").unwrap().as_f64(), Some(2.0));
+    }
+}
@@ -0,0 +1,95 @@
+use std::fs;
+use std::io::Write;
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use rand::RngCore;
+
+use crate::utils;
+use super::{temp_chunk_path, create_chunk_file, commit_chunk_file};
+
+// Marker kept next to the chunk folders of encrypted directory and remote stores, so a missing
+// or needless key is reported when the store is opened. Packs record it in their header.
+pub const ENCRYPTION_MARKER_FILE_NAME: &str = "encryption.marker";
+pub const ENCRYPTION_MARKER: &[u8] = b"aes-256-gcm\n";
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+// ChunkCipher AES-256-GCM applied to chunks after compression. An encrypted chunk is kept as
+// random nonce, ciphertext and tag. The chunk id is authenticated along with it, so a chunk
+// cannot be passed off under another id, while ids stay the hash of the plain chunk.
+#[derive(Clone)]
+pub struct ChunkCipher {
+    key: [u8;KEY_SIZE]
+}
+
+impl ChunkCipher {
+    // Key files hold 32 random bytes, e.g. from head -c 32 /dev/urandom
+    pub fn from_key_file(path: &str) -> ChunkCipher {
+        let bytes = match fs::read(path) {
+            Ok(b) => b,
+            Err(e) => {
+                panic!("Could not read encryption key {}, {:?}", path, e);
+            }
+        };
+        if bytes.len() != KEY_SIZE {
+            panic!("Encryption key {} must be exactly {} bytes, found {}", path, KEY_SIZE, bytes.len());
+        }
+        let mut key = [0;KEY_SIZE];
+        key.copy_from_slice(&bytes);
+        ChunkCipher { key }
+    }
+
+    pub fn encrypt(&self, id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut nonce = [0;NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut sealed = vec![0; NONCE_SIZE + data.len() + TAG_SIZE];
+        let (head, rest) = sealed.split_at_mut(NONCE_SIZE);
+        let (ciphertext, tag) = rest.split_at_mut(data.len());
+        head.copy_from_slice(&nonce);
+        AesGcm::new(KeySize::KeySize256, &self.key, &nonce, id).encrypt(data, ciphertext, tag);
+        sealed
+    }
+
+    // Panics if the chunk was altered, belongs to another id or was encrypted with another key
+    pub fn decrypt(&self, id: &[u8], sealed: &[u8]) -> Vec<u8> {
+        if sealed.len() < NONCE_SIZE + TAG_SIZE {
+            panic!("Encrypted chunk {} is truncated", utils::bytes_to_hex(id.to_vec()));
+        }
+        let (nonce, rest) = sealed.split_at(NONCE_SIZE);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+        let mut data = vec![0; ciphertext.len()];
+        if !AesGcm::new(KeySize::KeySize256, &self.key, nonce, id).decrypt(ciphertext, &mut data, tag) {
+            panic!("Chunk {} failed authentication, wrong key or tampered store", utils::bytes_to_hex(id.to_vec()));
+        }
+        data
+    }
+}
+
+// Checks the key given for a store against its marker, plain_chunks tells whether the store
+// may already hold unencrypted chunks. Returns whether the marker has to be written, which is
+// the case for stores encrypted from their first chunk on.
+pub fn check_encryption(store_path: &str, marked: bool, encryption: &Option<ChunkCipher>, plain_chunks: bool) -> bool {
+    match (marked, encryption) {
+        (true, None) => {
+            panic!("Store {} is encrypted, its key is needed", store_path);
+        },
+        (false, Some(_)) if plain_chunks => {
+            panic!("Store {} is not encrypted", store_path);
+        },
+        (false, Some(_)) => true,
+        _ => false
+    }
+}
+
+// Writes the marker of a local store, through a temporary file like chunks
+pub fn save_encryption_marker(store_path: &str) {
+    let path = std::path::Path::new(store_path).join(ENCRYPTION_MARKER_FILE_NAME);
+    fs::create_dir_all(store_path).expect("Error: Cannot create store folder");
+    let temp_path = temp_chunk_path(&path);
+    let mut f = create_chunk_file(temp_path.clone()).expect("Error: Cannot create encryption marker");
+    f.write_all(ENCRYPTION_MARKER).expect("Error: Cannot write encryption marker");
+    commit_chunk_file(f, &temp_path, &path);
+}
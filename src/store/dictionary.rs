@@ -0,0 +1,140 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use log::{info, debug};
+
+use super::{LocalStore, StoreOptions, ChunkCompression, temp_chunk_path, create_chunk_file, commit_chunk_file, encode_chunk, decode_chunk};
+
+// Dictionary of a store, kept next to the chunk folders
+pub const DICTIONARY_FILE_NAME: &str = "dictionary.zdict";
+pub const DICTIONARY_SIZE_DEFAULT: usize = 112640;
+pub const DICTIONARY_SAMPLES_DEFAULT: usize = 1000;
+
+// Dictionaries of remote stores by url, None for stores without one
+pub type SharedDictionaries = Arc<Mutex<HashMap<String, Option<Arc<Vec<u8>>>>>>;
+
+// zstd frame magic number, little endian
+const ZSTD_MAGIC: [u8;4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+pub fn dictionary_path(store_path: &str) -> PathBuf {
+    Path::new(store_path).join(DICTIONARY_FILE_NAME)
+}
+
+// Dictionary of a local store, None for stores without one
+pub fn load_dictionary(store_path: &str) -> Option<Arc<Vec<u8>>> {
+    let path = dictionary_path(store_path);
+    if !path.exists() {
+        return None;
+    }
+    match fs::read(&path) {
+        Ok(dictionary) => {
+            info!("Using dictionary {:?}", path);
+            Some(Arc::new(dictionary))
+        },
+        Err(e) => {
+            panic!("Could not read dictionary {:?}, {:?}", path, e);
+        }
+    }
+}
+
+// Dictionary of a remote store at url, fetched only by the first store made from options
+pub fn shared_dictionary<F: FnOnce() -> Option<Arc<Vec<u8>>>>(options: &StoreOptions, url: &str, fetch: F) -> Option<Arc<Vec<u8>>> {
+    let mut dictionaries = options.dictionaries.lock().unwrap();
+    dictionaries.entry(String::from(url)).or_insert_with(fetch).clone()
+}
+
+// Writes the dictionary of a local store, through a temporary file like chunks
+pub fn save_dictionary(store_path: &str, dictionary: &[u8]) {
+    let path = dictionary_path(store_path);
+    fs::create_dir_all(store_path).expect("Error: Cannot create store folder");
+    let temp_path = temp_chunk_path(&path);
+    let mut f = create_chunk_file(temp_path.clone()).expect("Error: Cannot create dictionary file");
+    f.write_all(dictionary).expect("Error: Cannot write dictionary");
+    commit_chunk_file(f, &temp_path, &path);
+}
+
+// Whether a compressed chunk was written with a dictionary, read from the dictionary id
+// flag in the zstd frame header
+pub fn frame_has_dictionary(compressed: &[u8]) -> bool {
+    compressed.len() > 4 && compressed[..4] == ZSTD_MAGIC && compressed[4] & 0x03 != 0
+}
+
+// DictionaryStats Size of a store before and after recompressing it with a dictionary
+pub struct DictionaryStats {
+    pub chunks: u64,
+    pub recompressed_chunks: u64,
+    pub size_before: u64,
+    pub size_after: u64
+}
+
+// Trains a dictionary from an evenly spread sample of the chunks of a local store, writes it
+// into the store and recompresses all chunks with it. Chunks already compressed with the
+// dictionary are skipped, so an interrupted run can be repeated. A store which already has a
+// dictionary keeps it, replacing it would leave its chunks unreadable.
+pub fn train_dictionary(store: &mut LocalStore, samples: usize, dictionary_size: usize) -> DictionaryStats {
+    let level = match store.compression {
+        ChunkCompression::Zstd(level) => level,
+        ChunkCompression::ZstdDictionary(level, _) => level,
+        ChunkCompression::Uncompressed => {
+            panic!("Dictionaries need a compressed store");
+        }
+    };
+    let mut ids = store.chunk_ids();
+    // Sorted ids make the sample independent of directory order
+    ids.sort();
+    if ids.is_empty() {
+        panic!("Store {} has no chunks to train a dictionary from", store.path);
+    }
+
+    let dictionary = match load_dictionary(&store.path) {
+        Some(dictionary) => {
+            info!("Store {} already has a dictionary, recompressing with it", store.path);
+            dictionary
+        },
+        None => {
+            let step = std::cmp::max(1, ids.len() / std::cmp::max(1, samples));
+            let sample: Vec<Vec<u8>> = ids.iter().step_by(step).take(samples).map(|id| {
+                decode_chunk(&store.compression, &store.encryption, id, &store.read_raw(id))
+            }).collect();
+            info!("Training dictionary of up to {} bytes from {} chunks", dictionary_size, sample.len());
+            let dictionary = match zstd::dict::from_samples(&sample, dictionary_size) {
+                Ok(d) => d,
+                Err(e) => {
+                    panic!("Could not train dictionary, {:?}", e);
+                }
+            };
+            save_dictionary(&store.path, &dictionary);
+            Arc::new(dictionary)
+        }
+    };
+
+    // Chunks without a dictionary still decompress with one, so the store stays readable throughout
+    store.compression = ChunkCompression::ZstdDictionary(level, dictionary);
+    let mut stats = DictionaryStats { chunks: 0, recompressed_chunks: 0, size_before: 0, size_after: 0 };
+    for id in ids.iter() {
+        let stored = store.read_raw(id);
+        stats.chunks += 1;
+        stats.size_before += stored.len() as u64;
+        let compressed = match &store.encryption {
+            Some(cipher) => cipher.decrypt(&id[..], &stored),
+            None => stored.clone()
+        };
+        if frame_has_dictionary(&compressed) {
+            stats.size_after += stored.len() as u64;
+            continue;
+        }
+        let bytes = store.compression.decompress(&compressed[..]);
+        let recompressed = encode_chunk(&store.compression, &store.encryption, id, &bytes);
+        debug!("Recompressed chunk from {} to {} bytes", stored.len(), recompressed.len());
+        let chunk_path = store.chunk_path(id);
+        let temp_path = temp_chunk_path(&chunk_path);
+        let mut f = create_chunk_file(temp_path.clone()).expect("Error: Cannot create chunk file");
+        f.write_all(&recompressed).expect("Error: Cannot write chunk to file");
+        commit_chunk_file(f, &temp_path, &chunk_path);
+        stats.recompressed_chunks += 1;
+        stats.size_after += recompressed.len() as u64;
+    }
+    stats
+}
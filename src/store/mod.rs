@@ -1,42 +1,255 @@
-use std::fs::{DirBuilder, File};
-use crypto::sha2::Sha512Trunc256;
-use crypto::digest::Digest;
+use std::fs::{self, DirBuilder, File};
 use std::path::{Path, PathBuf};
 use zstd::Encoder;
 use std::io;
 use std::io::Error;
-use log::{info, debug};
-use url::{Url, ParseError};
-use crate::utils;
-use std::io::Read;
+use log::{info, debug, warn};
+use url::Url;
+use crate::digest::ChunkDigest;
+use std::io::{Read, Write};
 use zstd::Decoder;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub fn get_suitable_store(path: &str, min: u64, max: u64, avg: u64) -> Box<Store> {
+mod s3;
+mod sftp;
+mod pack;
+mod dictionary;
+mod crypt;
+#[cfg(test)]
+mod test_server;
+pub use self::s3::S3Store;
+pub use self::sftp::SFTPStore;
+pub use self::pack::{PackStore, convert_store, is_pack_path};
+pub use self::crypt::{ChunkCipher, ENCRYPTION_MARKER_FILE_NAME};
+use self::crypt::ENCRYPTION_MARKER;
+pub use self::dictionary::{SharedDictionaries, train_dictionary, load_dictionary, save_dictionary, DICTIONARY_SIZE_DEFAULT, DICTIONARY_SAMPLES_DEFAULT};
+
+pub fn get_suitable_store(path: &str, min: u64, max: u64, avg: u64, options: &StoreOptions) -> Box<dyn Store> {
     match Url::parse(String::from(path).trim_end_matches("/")) {
         Ok(url) => {
             if url.scheme() == "http" || url.scheme() == "https" {
                 info!("path {}", path);
-                Box::new(RemoteHTTPStore::new(path,min, max, avg))
+                Box::new(RemoteHTTPStore::new(path,min, max, avg, options))
+            } else if url.scheme() == "s3" {
+                info!("s3 store {}", path);
+                Box::new(S3Store::new(&url, min, max, avg, options))
+            } else if url.scheme() == "ssh" || url.scheme() == "sftp" {
+                info!("ssh store {}", path);
+                Box::new(SFTPStore::new(&url, min, max, avg, options))
             } else {
-                panic!("store scheme not supported")
+                // Not a scheme we know, a local path like foo:bar
+                get_local_store(path, min, max, avg, options)
             }
         },
         Err(_) => {
-            info!("localfile system store");
-            Box::new(LocalStore::new(path, min, max, avg))
+            get_local_store(path, min, max, avg, options)
+        }
+    }
+}
+
+fn get_local_store(path: &str, min: u64, max: u64, avg: u64, options: &StoreOptions) -> Box<dyn Store> {
+    if is_pack_path(path) {
+        info!("pack file store");
+        Box::new(PackStore::new(path, min, max, avg, options))
+    } else {
+        info!("localfile system store");
+        let mut store = LocalStore::new(path, min, max, avg);
+        store.compression = options.compression().with_dictionary(load_dictionary(path));
+        store.encryption = options.encryption();
+        store.digest = options.digest;
+        store.check_encryption(options.read_only);
+        Box::new(store)
+    }
+}
+
+// StoreOptions Options for stores which need more than a path to be reached
+#[derive(Clone, Default)]
+pub struct StoreOptions {
+    // PEM bundle of CA certificates trusted in addition to the system ones
+    pub ca_cert: Option<String>,
+    // PEM client certificate and PKCS#8 key for mutual TLS
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    // Accept any server certificate, host name or ssh host key, only meant for lab setups
+    pub insecure_skip_verify: bool,
+    // Authorization sent along with every request
+    pub auth: Option<HTTPAuth>,
+    // Extra headers sent along with every request
+    pub headers: Vec<(String, String)>,
+    // Endpoint and region of s3 stores, defaults to AWS when not set
+    pub s3_endpoint: Option<String>,
+    pub s3_region: Option<String>,
+    // Credentials file of s3 stores, in the format of ~/.aws/credentials
+    pub s3_credentials_file: Option<String>,
+    // Private key for ssh stores, the ssh agent is used when not set
+    pub ssh_key: Option<String>,
+    // zstd level for new chunks, ZSTD_LEVEL_DEFAULT when not set
+    pub compression_level: Option<i32>,
+    // Store keeps chunks uncompressed
+    pub uncompressed: bool,
+    // Key file for stores keeping chunks encrypted
+    pub encryption_key: Option<String>,
+    // Chunks are only read, pack stores are opened read-only and have to exist
+    pub read_only: bool,
+    // Hash deriving ids of new chunks
+    pub digest: ChunkDigest,
+    // Dictionaries of remote stores by url, each is fetched once and shared by all stores made
+    // from these options, like the workers of one command
+    pub dictionaries: SharedDictionaries
+}
+
+impl StoreOptions {
+    pub fn compression(&self) -> ChunkCompression {
+        if self.uncompressed {
+            ChunkCompression::Uncompressed
+        } else {
+            ChunkCompression::Zstd(self.compression_level.unwrap_or(ZSTD_LEVEL_DEFAULT))
+        }
+    }
+
+    pub fn encryption(&self) -> Option<ChunkCipher> {
+        self.encryption_key.as_ref().map(|key| ChunkCipher::from_key_file(key))
+    }
+}
+
+// HTTPAuth Credentials for the Authorization header
+#[derive(Clone)]
+pub enum HTTPAuth {
+    Basic { user: String, password: String },
+    Bearer(String)
+}
+
+impl HTTPAuth {
+    // Parses "user:password" as found in credential files and variables
+    pub fn basic_from_str(credentials: &str) -> HTTPAuth {
+        match credentials.find(':') {
+            Some(i) => HTTPAuth::Basic {
+                user: String::from(&credentials[..i]),
+                password: String::from(&credentials[i+1..])
+            },
+            None => {
+                panic!("Basic auth credentials must be of the form user:password");
+            }
+        }
+    }
+
+    fn header_value(&self) -> String {
+        use rustc_serialize::base64::{ToBase64, STANDARD};
+        match self {
+            HTTPAuth::Basic { user, password } => {
+                format!("Basic {}", format!("{}:{}", user, password).as_bytes().to_base64(STANDARD))
+            },
+            HTTPAuth::Bearer(token) => {
+                format!("Bearer {}", token)
+            }
+        }
+    }
+}
+
+pub const ZSTD_LEVEL_DEFAULT: i32 = 21;
+
+// ChunkCompression How chunks are kept in a store, zstd compressed or uncompressed as desync
+// allows it. Uncompressed chunk files carry no .cacnk extension. Chunks compressed with a
+// dictionary trained by train-dictionary cannot be read without that dictionary.
+#[derive(Clone)]
+pub enum ChunkCompression {
+    Zstd(i32),
+    ZstdDictionary(i32, Arc<Vec<u8>>),
+    Uncompressed
+}
+
+impl ChunkCompression {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ChunkCompression::Zstd(_) | ChunkCompression::ZstdDictionary(_, _) => "cacnk",
+            ChunkCompression::Uncompressed => ""
+        }
+    }
+
+    // Switches zstd compression to the dictionary of a store, if it has one
+    pub fn with_dictionary(self, dictionary: Option<Arc<Vec<u8>>>) -> ChunkCompression {
+        match (self, dictionary) {
+            (ChunkCompression::Zstd(level), Some(dictionary)) => ChunkCompression::ZstdDictionary(level, dictionary),
+            (compression, _) => compression
+        }
+    }
+
+    // File name of a chunk given its hex id
+    pub fn file_name(&self, chunk_name: &str) -> String {
+        match self {
+            ChunkCompression::Zstd(_) | ChunkCompression::ZstdDictionary(_, _) => format!("{}.{}", chunk_name, self.extension()),
+            ChunkCompression::Uncompressed => String::from(chunk_name)
         }
     }
+
+    pub fn compress(&self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            ChunkCompression::Zstd(level) => {
+                let mut encoder = Encoder::new(Vec::new(), *level).unwrap();
+                io::copy(&mut &bytes[..], &mut encoder).expect("Error: Cannot compress chunk data");
+                encoder.finish().expect("Error: Cannot finish zstd encoding on chunk data")
+            },
+            ChunkCompression::ZstdDictionary(level, dictionary) => {
+                let mut encoder = Encoder::with_dictionary(Vec::new(), *level, &dictionary[..]).unwrap();
+                io::copy(&mut &bytes[..], &mut encoder).expect("Error: Cannot compress chunk data");
+                encoder.finish().expect("Error: Cannot finish zstd encoding on chunk data")
+            },
+            ChunkCompression::Uncompressed => bytes.to_vec()
+        }
+    }
+
+    pub fn decompress<R: Read>(&self, mut reader: R) -> Vec<u8> {
+        let mut uncompressed = Vec::new();
+        match self {
+            ChunkCompression::Zstd(_) => {
+                let mut decoder = Decoder::new(reader).unwrap();
+                io::copy(&mut decoder, &mut uncompressed).expect("Error: Cannot decompress data");
+            },
+            ChunkCompression::ZstdDictionary(_, dictionary) => {
+                let mut decoder = Decoder::with_dictionary(io::BufReader::new(reader), &dictionary[..]).unwrap();
+                io::copy(&mut decoder, &mut uncompressed).expect("Error: Cannot decompress data");
+            },
+            ChunkCompression::Uncompressed => {
+                reader.read_to_end(&mut uncompressed).expect("Error: Cannot read chunk data");
+            }
+        }
+        uncompressed
+    }
+}
+
+// Chunk as kept in a store, compressed and then encrypted for stores with a key
+pub fn encode_chunk(compression: &ChunkCompression, encryption: &Option<ChunkCipher>, id: &[u8;32], bytes: &[u8]) -> Vec<u8> {
+    let compressed = compression.compress(bytes);
+    match encryption {
+        Some(cipher) => cipher.encrypt(&id[..], &compressed),
+        None => compressed
+    }
+}
+
+pub fn decode_chunk(compression: &ChunkCompression, encryption: &Option<ChunkCipher>, id: &[u8;32], data: &[u8]) -> Vec<u8> {
+    match encryption {
+        Some(cipher) => compression.decompress(&cipher.decrypt(&id[..], data)[..]),
+        None => compression.decompress(data)
+    }
 }
 
 // StoreStats Store the stats for current store
+#[derive(Clone)]
 pub struct StoreStats {
-    count: u64,
-    min_size: u64,
-    max_size: u64,
-    avg_size: u64,
-    processed_bytes: u64,
-    new_chunks_count: u64
+    pub count: u64,
+    // Chunk size limits the store was opened with, not read by the reports yet
+    #[allow(dead_code)]
+    pub min_size: u64,
+    #[allow(dead_code)]
+    pub max_size: u64,
+    #[allow(dead_code)]
+    pub avg_size: u64,
+    pub processed_bytes: u64,
+    pub new_chunks_count: u64,
+    // Uncompressed and stored size of the new chunks
+    pub new_bytes: u64,
+    pub stored_bytes: u64
 }
 impl StoreStats {
     pub fn new(min: u64, max: u64, avg: u64) -> StoreStats {
@@ -46,36 +259,55 @@ impl StoreStats {
             max_size: max,
             avg_size: avg,
             processed_bytes: 0,
-            new_chunks_count: 0
+            new_chunks_count: 0,
+            new_bytes: 0,
+            stored_bytes: 0
         }
     }
     pub fn add_item(&mut self, processed_bytes: u64) {
         self.processed_bytes += processed_bytes;
         self.count += 1;
     }
-    pub fn add_new_item(&mut self, processed_bytes: u64) {
+    pub fn add_new_item(&mut self, processed_bytes: u64, stored_bytes: u64) {
         self.processed_bytes += processed_bytes;
         self.count += 1;
         self.new_chunks_count += 1;
-    }    
+        self.new_bytes += processed_bytes;
+        self.stored_bytes += stored_bytes;
+    }
+    // Adds the counts of another store, e.g. of a worker
+    pub fn add(&mut self, other: &StoreStats) {
+        self.count += other.count;
+        self.processed_bytes += other.processed_bytes;
+        self.new_chunks_count += other.new_chunks_count;
+        self.new_bytes += other.new_bytes;
+        self.stored_bytes += other.stored_bytes;
+    }
 }
 
 
-pub trait Store {
+// Stores are handed to worker threads by the chunker
+pub trait Store: Send {
     fn create(&self, path: &str) -> PathBuf;
     fn write_item(&mut self, bytes: Vec<u8>) -> [u8;32];
     fn read_item(&mut self, id: Vec<u8>) -> Vec<u8>;
+    // Counts of chunks written, None for stores which don't keep them
+    fn stats(&self) -> Option<&StoreStats> {
+        None
+    }
 }
 
 // DummyStore
 pub struct DummyStore {
-    pub stats: StoreStats
+    pub stats: StoreStats,
+    pub digest: ChunkDigest
 }
 
 impl DummyStore {
     pub fn new(min:u64, max: u64, avg: u64) -> DummyStore {
         DummyStore{
-            stats: StoreStats::new(min, max, avg)
+            stats: StoreStats::new(min, max, avg),
+            digest: ChunkDigest::default()
         }
     }
 }
@@ -85,39 +317,146 @@ impl Store for DummyStore {
         PathBuf::new()
     }
     fn write_item(&mut self, bytes: Vec<u8>) -> [u8;32] {
-        // TODO: This may not be needed, have to refactor to trait accordingly
-        let mut hasher = Sha512Trunc256::new();
-        hasher.input(&bytes);
-        // let hash_value = hasher.result_str();
-        // self.stats.add_item(bytes.len() as u64);
-        // println!("{:70} {:20}", hash_value, bytes.len());
-        let mut hash_bytes: [u8;32] = [0;32];
-        hasher.result(&mut hash_bytes);
-        hash_bytes
+        self.stats.add_item(bytes.len() as u64);
+        self.digest.sum(&bytes)
     }
-    fn read_item(&mut self, id: Vec<u8>) -> Vec<u8> {
+    fn read_item(&mut self, _id: Vec<u8>) -> Vec<u8> {
         Vec::new()
     }
+    fn stats(&self) -> Option<&StoreStats> {
+        Some(&self.stats)
+    }
 }
 
 // LocalStore 
 pub struct LocalStore {
     pub path: String,
-    pub stats: StoreStats
+    pub stats: StoreStats,
+    pub compression: ChunkCompression,
+    pub encryption: Option<ChunkCipher>,
+    pub digest: ChunkDigest
 }
 
 impl LocalStore {
     pub fn new(path: &str, min:u64, max: u64, avg: u64) -> LocalStore {
         LocalStore {
             path: String::from(path),
-            stats: StoreStats::new(min, max, avg)
+            stats: StoreStats::new(min, max, avg),
+            compression: ChunkCompression::Zstd(ZSTD_LEVEL_DEFAULT),
+            encryption: None,
+            digest: ChunkDigest::default()
+        }
+    }
+
+    pub fn chunk_path(&self, id: &[u8;32]) -> PathBuf {
+        use rustc_serialize::hex::ToHex;
+        let chunk_name = id[..].to_hex();
+        let (sub_dir_name,_) = chunk_name.split_at(4);
+        let mut full_path = PathBuf::new();
+        full_path.push(&self.path);
+        full_path.push(sub_dir_name);
+        full_path.push(self.compression.file_name(&chunk_name));
+        full_path
+    }
+
+    // Ids of all chunks found in the store folders
+    pub fn chunk_ids(&self) -> Vec<[u8;32]> {
+        use rustc_serialize::hex::FromHex;
+        let mut ids = Vec::new();
+        let sub_dirs = match fs::read_dir(&self.path) {
+            Ok(d) => d,
+            Err(e) => {
+                panic!("Could not list store {}, {:?}", self.path, e);
+            }
+        };
+        for sub_dir in sub_dirs.filter_map(|d| d.ok()).filter(|d| d.path().is_dir()) {
+            for entry in fs::read_dir(sub_dir.path()).unwrap().filter_map(|e| e.ok()) {
+                let path = entry.path();
+                if is_temp_chunk_file(&path) || path.extension().map_or("", |e| e.to_str().unwrap_or("")) != self.compression.extension() {
+                    continue;
+                }
+                let id = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.from_hex().ok());
+                match id {
+                    Some(ref id) if id.len() == 32 => {
+                        let mut chunk_id: [u8;32] = [0;32];
+                        chunk_id.copy_from_slice(id);
+                        ids.push(chunk_id);
+                    },
+                    _ => {
+                        debug!("Skipping unexpected file {:?} in store", path);
+                    }
+                }
+            }
+        }
+        ids
+    }
+
+    // Checks the key of the store against its encryption marker, marks a new store encrypted
+    pub fn check_encryption(&self, read_only: bool) {
+        let marked = Path::new(&self.path).join(ENCRYPTION_MARKER_FILE_NAME).exists();
+        // Only listed when a key is given for a store without the marker
+        let plain_chunks = !marked && self.encryption.is_some()
+            && (read_only || (Path::new(&self.path).is_dir() && !self.chunk_ids().is_empty()));
+        if crypt::check_encryption(&self.path, marked, &self.encryption, plain_chunks) {
+            crypt::save_encryption_marker(&self.path);
+        }
+    }
+
+    // Removes temporary files left behind by interrupted writes, returns how many were removed.
+    // Must not run while another process writes to the store.
+    pub fn remove_temp_files(&self) -> u64 {
+        let mut removed = 0;
+        let sub_dirs = match fs::read_dir(&self.path) {
+            Ok(d) => d,
+            Err(e) => {
+                panic!("Could not list store {}, {:?}", self.path, e);
+            }
+        };
+        for sub_dir in sub_dirs.filter_map(|d| d.ok()).filter(|d| d.path().is_dir()) {
+            for entry in fs::read_dir(sub_dir.path()).unwrap().filter_map(|e| e.ok()) {
+                let path = entry.path();
+                if is_temp_chunk_file(&path) {
+                    info!("Removing stale temporary file {:?}", path);
+                    fs::remove_file(&path).expect("Error: Cannot remove temporary file");
+                    removed += 1;
+                }
+            }
+        }
+        removed
+    }
+
+    // Compressed (and encrypted) chunk as stored
+    pub fn read_raw(&self, id: &[u8;32]) -> Vec<u8> {
+        match fs::read(self.chunk_path(id)) {
+            Ok(data) => data,
+            Err(e) => {
+                panic!("Could not open file to read, {:?}", e);
+            }
+        }
+    }
+
+    // Stores an already compressed (and encrypted) chunk
+    pub fn write_raw(&self, id: &[u8;32], compressed: &[u8]) {
+        let chunk_path = self.chunk_path(id);
+        if !chunk_path.exists() {
+            DirBuilder::new().recursive(true).create(chunk_path.parent().unwrap()).expect("Error: Cannot create store folder");
+            let temp_path = temp_chunk_path(&chunk_path);
+            match create_chunk_file(temp_path.clone()) {
+                Ok(mut f) => {
+                    f.write_all(compressed).expect("Error: Cannot write chunk to file");
+                    commit_chunk_file(f, &temp_path, &chunk_path);
+                },
+                Err(e) => {
+                    panic!("Could not create file to write chunk, {:?}", e)
+                }
+            }
         }
     }
 }
 
 impl Store for LocalStore {
     fn create(&self, path: &str) -> PathBuf {
-        let mut base_path = Path::new(&self.path);
+        let base_path = Path::new(&self.path);
         let final_path = base_path.join(path);
         match DirBuilder::new().recursive(true).create(final_path.as_path()) {
             Ok(()) => {
@@ -132,52 +471,46 @@ impl Store for LocalStore {
 
     fn write_item(&mut self, bytes: Vec<u8>) -> [u8;32] {
         // Write to file 
-        let mut hasher = Sha512Trunc256::new();
-        hasher.input(&bytes);
-        let hash_value = hasher.result_str();
+        use rustc_serialize::hex::ToHex;
+        let hash_bytes = self.digest.sum(&bytes);
+        let hash_value = hash_bytes[..].to_hex();
         let (sub_dir_name,_) = hash_value.split_at(4);
         let mut chunk_folder = self.create(sub_dir_name);
-        chunk_folder.push(hash_value);
-        chunk_folder.set_extension("cacnk");
+        chunk_folder.push(self.compression.file_name(&hash_value));
  
         if chunk_folder.exists() {
             self.stats.add_item(bytes.len() as u64);
         } else {
-            let new_chunk_file = create_chunk_file(chunk_folder);
-            match new_chunk_file {
-                Ok(f) => {
-                    let mut encoder = Encoder::new(f,21).unwrap();
-                    io::copy(&mut bytes.as_slice(), &mut encoder).expect("Error: Cannot write compressed data to file");
-                    encoder.finish().expect("Error: Cannot finish zstd encoding on chunk data");
+            let temp_path = temp_chunk_path(&chunk_folder);
+            let new_chunk_file = create_chunk_file(temp_path.clone());
+            let stored_size = match new_chunk_file {
+                Ok(mut f) => {
+                    let encoded = encode_chunk(&self.compression, &self.encryption, &hash_bytes, &bytes);
+                    f.write_all(&encoded).expect("Error: Cannot write compressed data to file");
+                    commit_chunk_file(f, &temp_path, &chunk_folder);
+                    encoded.len() as u64
                 },
                 Err(e) => {
                     panic!("Could not create file to write chunk, {:?}", e)
                 }
-            }
-            self.stats.add_new_item(bytes.len() as u64);
+            };
+            self.stats.add_new_item(bytes.len() as u64, stored_size);
         };
-
-        let mut hash_bytes: [u8;32] = [0;32];
-        hasher.result(&mut hash_bytes);
         hash_bytes
     }
 
+    fn stats(&self) -> Option<&StoreStats> {
+        Some(&self.stats)
+    }
+
     fn read_item(&mut self, id: Vec<u8>) -> Vec<u8> {
-        use rustc_serialize::hex::ToHex;
-        let chunk_name = id[..].to_hex();
-        let (sub_dir_name,_) = chunk_name.split_at(4);
-        let mut full_path = PathBuf::new();
-        full_path.push(&self.path);
-        full_path.push(sub_dir_name);
-        full_path.push(chunk_name);
-        full_path.set_extension("cacnk");
+        let mut chunk_id: [u8;32] = [0;32];
+        chunk_id.copy_from_slice(&id[..32]);
+        let full_path = self.chunk_path(&chunk_id);
         info!("fullpath, {:?}",full_path);
-        match File::open(full_path) {
-            Ok(mut file) => {
-                let mut uncompressed = Vec::new();
-                let mut decoder = Decoder::new(file).unwrap();
-                io::copy(&mut decoder, &mut uncompressed).expect("Error: Cannot decompress data to file");
-                uncompressed
+        match fs::read(full_path) {
+            Ok(data) => {
+                decode_chunk(&self.compression, &self.encryption, &chunk_id, &data)
             },
             Err(e) => {
                 panic!("Could not open file to read, {:?}", e);
@@ -190,104 +523,442 @@ pub fn create_chunk_file(filename: PathBuf) -> Result<File, Error> {
     File::create(filename)
 }
 
-use hyper::{Client,Uri,Body,Request};
+// Chunks are written under a hidden temporary name next to their final path
+// and only renamed into place once complete, see commit_chunk_file
+const TEMP_CHUNK_SUFFIX: &str = ".tmp";
+
+static TEMP_CHUNK_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub fn temp_chunk_path(chunk_path: &Path) -> PathBuf {
+    let name = chunk_path.file_name().unwrap().to_string_lossy();
+    // Unique per process and writer, workers may race on the same chunk
+    let n = TEMP_CHUNK_COUNTER.fetch_add(1, Ordering::SeqCst);
+    chunk_path.with_file_name(format!(".{}.{}-{}{}", name, std::process::id(), n, TEMP_CHUNK_SUFFIX))
+}
+
+// Syncs a fully written temporary chunk file and renames it to its final path,
+// a crash before this point leaves only a temporary file behind
+pub fn commit_chunk_file(f: File, temp_path: &Path, chunk_path: &Path) {
+    f.sync_all().expect("Error: Cannot sync chunk file");
+    drop(f);
+    if let Err(e) = fs::rename(temp_path, chunk_path) {
+        panic!("Could not move chunk into place {:?}, {:?}", chunk_path, e);
+    }
+}
+
+pub fn is_temp_chunk_file(path: &Path) -> bool {
+    path.file_name().and_then(|n| n.to_str()).is_some_and(|n| {
+        n.starts_with('.') && n.ends_with(TEMP_CHUNK_SUFFIX)
+    })
+}
+
+use hyper::{Client,Body,Request,Method,StatusCode};
 use hyper::client::{HttpConnector};
-use hyper::rt::{self, Future, Stream};
-use std::io::Write;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, IF_NONE_MATCH};
+use hyper_tls::HttpsConnector;
+use native_tls::{TlsConnector, Certificate, Identity};
+use hyper::rt::{Future, Stream};
 use bytes::Bytes;
-use bytes::Buf;
-use std::io::{Cursor};
+
+// RemoteHTTPClient Blocking http(s) client carrying the TLS setup and headers of a store
+#[derive(Clone)]
+pub struct RemoteHTTPClient {
+    pub client: Client<HttpsConnector<HttpConnector>>,
+    pub headers: HeaderMap
+}
+
+impl RemoteHTTPClient {
+    pub fn new(options: &StoreOptions) -> RemoteHTTPClient {
+        // Every request runs on its own tokio runtime, so pooled connections
+        // would outlive the runtime driving them; keep-alive is disabled.
+        let client = Client::builder()
+            .keep_alive(false)
+            .build(https_connector(options));
+        let mut headers = HeaderMap::new();
+        for (name, value) in options.headers.iter() {
+            match (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+                (Ok(n), Ok(v)) => {
+                    headers.append(n, v);
+                },
+                _ => {
+                    panic!("Invalid http header {}", name);
+                }
+            }
+        }
+        if let Some(auth) = &options.auth {
+            match HeaderValue::from_str(&auth.header_value()) {
+                Ok(mut v) => {
+                    v.set_sensitive(true);
+                    headers.insert(AUTHORIZATION, v);
+                },
+                Err(_) => {
+                    panic!("Invalid characters in http credentials");
+                }
+            }
+        }
+        RemoteHTTPClient {
+            client,
+            headers
+        }
+    }
+
+    pub fn request(&self, method: Method, url: &str, body: Vec<u8>) -> (StatusCode, Bytes) {
+        self.request_with_headers(method, url, HeaderMap::new(), body)
+    }
+
+    // Sends a request with additional headers, which take precedence over the configured ones
+    pub fn request_with_headers(&self, method: Method, url: &str, headers: HeaderMap, body: Vec<u8>) -> (StatusCode, Bytes) {
+        let mut req = match Request::builder().method(method).uri(url).body(Body::from(body)) {
+            Ok(req) => req,
+            Err(e) => {
+                panic!("Invalid remote url {}, {:?}", url, e);
+            }
+        };
+        for (name, value) in self.headers.iter() {
+            req.headers_mut().append(name.clone(), value.clone());
+        }
+        for (name, value) in headers.iter() {
+            req.headers_mut().insert(name.clone(), value.clone());
+        }
+
+        // Fixed tokio lifetime problems with below solution reference using Arc
+        // Ref: https://stackoverflow.com/questions/39473282/tokio-curl-capture-output-into-a-local-vec-may-outlive-borrowed-value
+        let response = Arc::new(Mutex::new(None));
+        let response_clone = response.clone();
+        let response_err_clone = response.clone();
+        let fut = self.client.request(req)
+            .and_then(|res| {
+                let status = res.status();
+                // asynchronously concatenate chunks of the body
+                res.into_body().concat2().map(move |body| (status, body.into_bytes()))
+            })
+            .map(move |res| {
+                *response_clone.lock().unwrap() = Some(Ok(res));
+            })
+            .map_err(move |e| {
+                *response_err_clone.lock().unwrap() = Some(Err(e));
+            });
+
+        // TODO: start runtime once in the beginning of program instead of bootstrapping everytime.
+        tokio::run(fut);
+        let result = response.lock().unwrap().take();
+        match result {
+            Some(Ok(res)) => res,
+            Some(Err(e)) => {
+                panic!("Remote request to {} failed, {:?}", url, e);
+            },
+            None => {
+                panic!("Remote request to {} did not complete", url);
+            }
+        }
+    }
+
+    pub fn get(&self, url: &str) -> Bytes {
+        let (status, body) = self.request(Method::GET, url, Vec::new());
+        if ! status.is_success() {
+            panic!("Remote fetch of {} failed with {:?}", url, status);
+        }
+        body
+    }
+}
 
 // RemoteHTTPStore
 pub struct RemoteHTTPStore {
     pub path: String,
     pub stats: StoreStats,
-    pub client: Client<HttpConnector>
+    pub client: RemoteHTTPClient,
+    pub compression: ChunkCompression,
+    pub encryption: Option<ChunkCipher>,
+    pub digest: ChunkDigest
 }
 
 impl RemoteHTTPStore {
-    pub fn new(path: &str, min: u64, max: u64, avg: u64) -> RemoteHTTPStore {
-        let client = Client::new();
-        RemoteHTTPStore {
+    pub fn new(path: &str, min: u64, max: u64, avg: u64, options: &StoreOptions) -> RemoteHTTPStore {
+        let mut store = RemoteHTTPStore {
             path: String::from(path),
             stats: StoreStats::new(min, max, avg),
-            client: client
+            client: RemoteHTTPClient::new(options),
+            compression: options.compression(),
+            encryption: options.encryption(),
+            digest: options.digest
+        };
+        store.check_encryption(options.read_only);
+        if let ChunkCompression::Zstd(_) = store.compression {
+            let url = store.store_url(dictionary::DICTIONARY_FILE_NAME);
+            let dictionary = dictionary::shared_dictionary(options, &url, || store.load_dictionary());
+            store.compression = store.compression.with_dictionary(dictionary);
+        }
+        store
+    }
+
+    // Url of a file below the store path
+    fn store_url(&self, file_path: &str) -> String {
+        let mut url = Url::parse(&self.path).unwrap();
+        let full_path = format!("{}/{}", url.path().trim_end_matches('/'), file_path);
+        url.set_path(&full_path);
+        String::from(url.as_str())
+    }
+
+    fn chunk_url(&self, chunk_name: &str) -> String {
+        let (sub_dir_name,_) = chunk_name.split_at(4);
+        self.store_url(&format!("{}/{}", sub_dir_name, self.compression.file_name(chunk_name)))
+    }
+
+    // Checks the key of the store against its encryption marker, marks a new store encrypted
+    fn check_encryption(&self, read_only: bool) {
+        let url = self.store_url(ENCRYPTION_MARKER_FILE_NAME);
+        let (status, _) = self.client.request(Method::GET, &url, Vec::new());
+        let marked = if status == StatusCode::NOT_FOUND {
+            false
+        } else if status.is_success() {
+            true
+        } else {
+            panic!("Could not read encryption marker {}, {:?}", url, status);
+        };
+        // Chunks of a remote store can't be listed, one which is written to is taken to be new
+        if crypt::check_encryption(&self.path, marked, &self.encryption, read_only) {
+            let mut headers = HeaderMap::new();
+            headers.insert(IF_NONE_MATCH, HeaderValue::from_static("*"));
+            let (status, _) = self.client.request_with_headers(Method::PUT, &url, headers, ENCRYPTION_MARKER.to_vec());
+            if !status.is_success() && status != StatusCode::PRECONDITION_FAILED {
+                panic!("Could not write encryption marker {}, {:?}", url, status);
+            }
+        }
+    }
+
+    // Dictionary of the store, None if the server has none
+    fn load_dictionary(&self) -> Option<Arc<Vec<u8>>> {
+        let url = self.store_url(dictionary::DICTIONARY_FILE_NAME);
+        let (status, body) = self.client.request(Method::GET, &url, Vec::new());
+        if status == StatusCode::NOT_FOUND {
+            None
+        } else if status.is_success() {
+            info!("Using dictionary {}", url);
+            Some(Arc::new(body.to_vec()))
+        } else {
+            panic!("Could not read dictionary {}, {:?}", url, status);
         }
     }
 }
 
 impl Store for RemoteHTTPStore {
     fn create(&self, path: &str) -> PathBuf {
-        let req = Request::builder()
-            .method("HEAD")
-            .uri(&self.path)
-            .body(Body::empty()).unwrap();
-
-        let future = self.client.request(req);
-        rt::run(
-            future.map(|res| {
-                if ! res.status().is_success() {
-                    panic!("Error while finding remote http store {:?}",res.status());    
-                }
-            })
-            .map_err(|err| {
-                panic!("Remote http store doesn't exist {:?}",err);
-            })
-        );
+        let (status, _) = self.client.request(Method::HEAD, &self.path, Vec::new());
+        if ! status.is_success() {
+            panic!("Error while finding remote http store {:?}", status);
+        }
         Path::new(path).to_path_buf()
     }
 
+    // Uploads new chunks with PUT, as desync does for http stores. If-None-Match keeps
+    // chunks the server already has from being sent again where the server supports it.
     fn write_item(&mut self, bytes: Vec<u8>) -> [u8;32] {
-        // TODO: Write to remote location, feature may not be needed
-        [0;32]
+        use rustc_serialize::hex::ToHex;
+        let hash_bytes = self.digest.sum(&bytes);
+        let url = self.chunk_url(&hash_bytes[..].to_hex());
+        let encoded = encode_chunk(&self.compression, &self.encryption, &hash_bytes, &bytes);
+        let stored_size = encoded.len() as u64;
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("*"));
+        let (status, _) = self.client.request_with_headers(Method::PUT, &url, headers, encoded);
+        if status == StatusCode::PRECONDITION_FAILED {
+            self.stats.add_item(bytes.len() as u64);
+        } else if status.is_success() {
+            self.stats.add_new_item(bytes.len() as u64, stored_size);
+        } else {
+            panic!("Could not write {} to http store, {:?}", url, status);
+        }
+        hash_bytes
+    }
+
+    fn stats(&self) -> Option<&StoreStats> {
+        Some(&self.stats)
     }
 
     fn read_item(&mut self, id: Vec<u8>) -> Vec<u8> {
         use rustc_serialize::hex::ToHex;
-        let chunk_name = id[..].to_hex();
-        let (sub_dir_name,_) = chunk_name.split_at(4);
-        let mut url = Url::parse(&self.path).unwrap();
-        let url_current_path = url.path();
-        let mut full_path = format!("{}/{}/{}.cacnk",url_current_path, sub_dir_name, chunk_name);
-        url.set_path(&full_path);
-        let uri = url.as_str().parse::<Uri>().unwrap();
-        
-        // Fixed tokio lifetime problems with below solution reference using Arc
-        // Ref: https://stackoverflow.com/questions/39473282/tokio-curl-capture-output-into-a-local-vec-may-outlive-borrowed-value
-        let final_bytes = Arc::new(Mutex::new(Vec::<u8>::new()));
-        let mut final_bytes_clone = final_bytes.clone();
-        let fut = fetch_chunk(uri)
-            .map(move |data| {
-                let mut bufReader = Cursor::new(data).reader();
-                let mut decoder = Decoder::new(bufReader).unwrap();
-                let mut final_bytes_clone = final_bytes_clone.lock().unwrap();
-                io::copy(&mut decoder, &mut *final_bytes_clone).expect("Error: Cannot decompress data");
-            })
-            .map_err(|e| {
-                panic!("Remote Fetch err {:?}",e);
-            });
+        let url = self.chunk_url(&id[..].to_hex());
+        let data = self.client.get(&url);
+        let mut chunk_id: [u8;32] = [0;32];
+        chunk_id.copy_from_slice(&id[..32]);
+        decode_chunk(&self.compression, &self.encryption, &chunk_id, &data[..])
+    }
+}
 
-        // TODO: start runtime once in the beginning of program instead of bootstrapping everytime.
-        tokio::run(fut);
-        let final_bytes_value = (*final_bytes.lock().unwrap()).clone();
-        final_bytes_value
+fn https_connector(options: &StoreOptions) -> HttpsConnector<HttpConnector> {
+    let mut builder = TlsConnector::builder();
+    if let Some(ca_cert) = &options.ca_cert {
+        let pem = read_pem_file(ca_cert);
+        let pem = String::from_utf8_lossy(&pem);
+        // A bundle may hold several certificates, native-tls parses only the first one
+        let marker = "-----END CERTIFICATE-----";
+        for cert in pem.split(marker).filter(|c| c.contains("-----BEGIN CERTIFICATE-----")) {
+            match Certificate::from_pem(format!("{}{}\n", cert, marker).as_bytes()) {
+                Ok(c) => {
+                    builder.add_root_certificate(c);
+                },
+                Err(e) => {
+                    panic!("Invalid CA certificate in {}, {:?}", ca_cert, e);
+                }
+            }
+        }
+    }
+    match (&options.client_cert, &options.client_key) {
+        (Some(cert), Some(key)) => {
+            match Identity::from_pkcs8(&read_pem_file(cert), &read_pem_file(key)) {
+                Ok(identity) => {
+                    builder.identity(identity);
+                },
+                Err(e) => {
+                    panic!("Invalid client certificate or key, {:?}", e);
+                }
+            }
+        },
+        (None, None) => {},
+        _ => {
+            panic!("Both client certificate and client key are needed for mutual TLS");
+        }
     }
+    if options.insecure_skip_verify {
+        warn!("Skipping TLS certificate verification for remote store");
+        builder.danger_accept_invalid_certs(true);
+        builder.danger_accept_invalid_hostnames(true);
+    }
+    let tls = match builder.build() {
+        Ok(tls) => tls,
+        Err(e) => {
+            panic!("Could not set up TLS for remote store, {:?}", e);
+        }
+    };
+    let mut http = HttpConnector::new(4);
+    http.enforce_http(false);
+    HttpsConnector::from((http, tls))
 }
 
-fn fetch_chunk(url: hyper::Uri) -> impl Future<Item=Bytes, Error=hyper::Error> {
-    let client = Client::new();
-    client
-        // Fetch the url...
-        .get(url)
-        // And then, if we get a response back...
-        .and_then(|res| {
-            // asynchronously concatenate chunks of the body
-            res.into_body().concat2()
-        })
-        // use the body after concatenation
-        .and_then(|body| {
-            // try to parse as json with serde_json
-            Ok(body.into_bytes())
-        })
+fn read_pem_file(path: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+    match File::open(path) {
+        Ok(mut f) => {
+            f.read_to_end(&mut buf).expect("Error: Cannot read PEM file");
+            buf
+        },
+        Err(e) => {
+            panic!("Could not open PEM file {}, {:?}", path, e);
+        }
+    }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_server::TestServer;
+
+    #[test]
+    fn http_store_writes_chunks_with_put_and_reads_them_back() {
+        let server = TestServer::start();
+        let options = StoreOptions {
+            auth: Some(HTTPAuth::Bearer(String::from("secret"))),
+            ..StoreOptions::default()
+        };
+        let mut store = RemoteHTTPStore::new(&format!("{}/store/", server.url), 0, 0, 0, &options);
+        let id = store.write_item(b"hello chunk".to_vec());
+        assert_eq!(store.write_item(b"hello chunk".to_vec()), id);
+        assert_eq!(store.read_item(id.to_vec()), b"hello chunk".to_vec());
+
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.count, stats.new_chunks_count), (2, 1));
+        let requests = server.requests();
+        assert_eq!(requests.iter().map(|r| r.method.as_str()).collect::<Vec<_>>(), vec!["GET", "GET", "PUT", "PUT", "GET"]);
+        assert_eq!(requests[0].path, "/store/encryption.marker");
+        assert_eq!(requests[1].path, "/store/dictionary.zdict");
+        let hex = rustc_serialize::hex::ToHex::to_hex(&id[..]);
+        assert_eq!(requests[2].path, format!("/store/{}/{}.cacnk", &hex[..4], hex));
+        assert_eq!(requests[2].headers.get("if-none-match").map(|v| v.as_str()), Some("*"));
+        assert_eq!(requests[2].headers.get("authorization").map(|v| v.as_str()), Some("Bearer secret"));
+    }
+
+    #[test]
+    fn http_store_reads_chunks_with_the_store_dictionary() {
+        let server = TestServer::start();
+        let dictionary: Vec<u8> = b"remote dictionary content ".iter().cycle().take(4096).cloned().collect();
+        server.objects.lock().unwrap().insert(String::from("/store/dictionary.zdict"), dictionary.clone());
+        let mut store = RemoteHTTPStore::new(&format!("{}/store", server.url), 0, 0, 0, &StoreOptions::default());
+        match &store.compression {
+            ChunkCompression::ZstdDictionary(_, d) => assert_eq!(&d[..], &dictionary[..]),
+            _ => panic!("dictionary of the http store was not loaded")
+        }
+        let id = store.write_item(b"remote dictionary content, compressed with it".to_vec());
+        assert_eq!(store.read_item(id.to_vec()), b"remote dictionary content, compressed with it".to_vec());
+
+        // Uncompressed stores have no use for a dictionary and don't ask for one
+        let uncompressed = StoreOptions { uncompressed: true, ..StoreOptions::default() };
+        RemoteHTTPStore::new(&format!("{}/plain", server.url), 0, 0, 0, &uncompressed);
+        assert!(server.requests().iter().all(|r| r.path != "/plain/dictionary.zdict"));
+    }
+
+    #[test]
+    fn worker_stores_share_the_dictionary_fetched_once() {
+        let server = TestServer::start();
+        let dictionary: Vec<u8> = b"shared dictionary content ".iter().cycle().take(4096).cloned().collect();
+        server.objects.lock().unwrap().insert(String::from("/store/dictionary.zdict"), dictionary.clone());
+        let options = StoreOptions::default();
+        let stores: Vec<RemoteHTTPStore> = (0..3).map(|_| RemoteHTTPStore::new(&format!("{}/store", server.url), 0, 0, 0, &options)).collect();
+        for store in stores.iter() {
+            match &store.compression {
+                ChunkCompression::ZstdDictionary(_, d) => assert_eq!(&d[..], &dictionary[..]),
+                _ => panic!("dictionary was not shared with the store")
+            }
+        }
+        assert_eq!(server.requests().iter().filter(|r| r.path == "/store/dictionary.zdict").count(), 1);
+
+        // Stores without a dictionary don't ask again either
+        (0..3).for_each(|_| { RemoteHTTPStore::new(&format!("{}/other", server.url), 0, 0, 0, &options); });
+        assert_eq!(server.requests().iter().filter(|r| r.path == "/other/dictionary.zdict").count(), 1);
+    }
+
+    fn panic_message<T, F: FnOnce() -> T + std::panic::UnwindSafe>(f: F) -> String {
+        match std::panic::catch_unwind(f) {
+            Ok(_) => panic!("did not panic"),
+            Err(e) => e.downcast_ref::<String>().cloned().unwrap_or_default()
+        }
+    }
+
+    #[test]
+    fn directory_store_records_its_encryption() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("key");
+        std::fs::write(&key_path, [3u8; 32]).unwrap();
+        let encrypted = StoreOptions { encryption_key: Some(String::from(key_path.to_str().unwrap())), ..StoreOptions::default() };
+        let path = String::from(dir.path().join("store").to_str().unwrap());
+        let id = get_suitable_store(&path, 0, 0, 0, &encrypted).write_item(b"secret chunk".to_vec());
+        assert!(dir.path().join("store").join(ENCRYPTION_MARKER_FILE_NAME).exists());
+        assert_eq!(get_suitable_store(&path, 0, 0, 0, &encrypted).read_item(id.to_vec()), b"secret chunk".to_vec());
+        let read_only = StoreOptions { read_only: true, ..StoreOptions::default() };
+        assert!(panic_message(|| get_suitable_store(&path, 0, 0, 0, &read_only)).contains("is encrypted, its key is needed"));
+
+        // Plain stores holding chunks are not turned into encrypted ones
+        let plain = String::from(dir.path().join("plain").to_str().unwrap());
+        get_suitable_store(&plain, 0, 0, 0, &StoreOptions::default()).write_item(b"plain chunk".to_vec());
+        assert!(panic_message(|| get_suitable_store(&plain, 0, 0, 0, &encrypted)).contains("is not encrypted"));
+        assert!(!dir.path().join("plain").join(ENCRYPTION_MARKER_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn http_store_records_its_encryption() {
+        let server = TestServer::start();
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("key");
+        std::fs::write(&key_path, [5u8; 32]).unwrap();
+        let encrypted = StoreOptions { encryption_key: Some(String::from(key_path.to_str().unwrap())), ..StoreOptions::default() };
+        let url = format!("{}/store", server.url);
+        let id = RemoteHTTPStore::new(&url, 0, 0, 0, &encrypted).write_item(b"secret chunk".to_vec());
+        assert_eq!(server.objects.lock().unwrap().get("/store/encryption.marker").map(|m| &m[..]), Some(ENCRYPTION_MARKER));
+        let read_only = StoreOptions { read_only: true, ..encrypted.clone() };
+        assert_eq!(RemoteHTTPStore::new(&url, 0, 0, 0, &read_only).read_item(id.to_vec()), b"secret chunk".to_vec());
+        assert!(panic_message(|| RemoteHTTPStore::new(&url, 0, 0, 0, &StoreOptions::default()).path).contains("is encrypted, its key is needed"));
+
+        let plain = format!("{}/plain", server.url);
+        assert!(panic_message(|| RemoteHTTPStore::new(&plain, 0, 0, 0, &read_only).path).contains("is not encrypted"));
+    }
+}
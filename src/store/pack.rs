@@ -0,0 +1,533 @@
+use std::fs::{File, OpenOptions};
+use std::collections::HashMap;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use byteorder::ByteOrder;
+use log::{info, debug, warn};
+
+use crate::utils;
+use crate::digest::ChunkDigest;
+use super::{Store, StoreStats, StoreOptions, LocalStore, ChunkCompression, ChunkCipher, ZSTD_LEVEL_DEFAULT, encode_chunk, decode_chunk, load_dictionary, save_dictionary};
+
+// Layout of a pack file, all numbers are little endian u64:
+//   header magic, flags, dictionary length, zstd dictionary
+//   compressed chunks, back to back
+//   table of entries: 32 byte chunk id, offset, length
+//   table offset, entry count, tail magic
+// Packs are only ever appended to. Chunks added later follow the previous table and are
+// covered by a new table written after them, so an interrupted write leaves the last
+// complete table intact and only loses the chunks added since.
+const PACK_HEADER_MAGIC: u64 = 0x6b636170636e7963;
+const PACK_TAIL_MAGIC: u64 = 0x6c696174636e7963;
+const PACK_HEADER_SIZE: u64 = 24;
+const PACK_TAIL_SIZE: u64 = 24;
+const PACK_ENTRY_SIZE: u64 = 48;
+const PACK_FLAG_UNCOMPRESSED: u64 = 0x1;
+const PACK_FLAG_ENCRYPTED: u64 = 0x2;
+// Read size when looking for the last complete table of a damaged pack
+const PACK_SCAN_BLOCK_SIZE: u64 = 1024 * 1024;
+pub const PACK_EXTENSION: &str = "capack";
+
+// PackStore Chunk store kept in a single file, handy for media that copes badly with many small files
+pub struct PackStore {
+    pub path: String,
+    pub stats: StoreStats,
+    pub file: File,
+    // Taken from the pack header, only the zstd level of new chunks comes from the options
+    pub compression: ChunkCompression,
+    pub encryption: Option<ChunkCipher>,
+    pub digest: ChunkDigest,
+    // chunk id -> (offset, length) of the compressed chunk
+    pub table: HashMap<[u8;32], (u64, u64)>,
+    // End of the last complete table, where new chunks are appended
+    end: u64,
+    dirty: bool,
+    read_only: bool
+}
+
+impl PackStore {
+    pub fn new(path: &str, min: u64, max: u64, avg: u64, options: &StoreOptions) -> PackStore {
+        let mut store = PackStore::open(path, min, max, avg, options.compression(), options.encryption(), options.read_only);
+        store.digest = options.digest;
+        store
+    }
+
+    // Opens a pack, creating it with the given compression and encryption if it does not exist.
+    // A read-only pack has to exist and is never written to.
+    pub fn open(path: &str, min: u64, max: u64, avg: u64, compression: ChunkCompression, encryption: Option<ChunkCipher>, read_only: bool) -> PackStore {
+        let exists = Path::new(path).exists();
+        let file = match OpenOptions::new().read(true).write(!read_only).create(!read_only).truncate(false).open(path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                panic!("Pack store {} does not exist", path);
+            },
+            Err(e) => {
+                panic!("Could not open pack store {}, {:?}", path, e);
+            }
+        };
+        let fresh = !read_only && (!exists || file.metadata().unwrap().len() == 0);
+        let mut store = PackStore {
+            path: String::from(path),
+            stats: StoreStats::new(min, max, avg),
+            file,
+            compression,
+            encryption,
+            digest: ChunkDigest::default(),
+            table: HashMap::new(),
+            end: 0,
+            dirty: false,
+            read_only
+        };
+        if fresh {
+            store.write_header();
+            // An empty table makes the pack valid from the start
+            store.write_table();
+        } else {
+            store.read_header();
+            store.read_table();
+        }
+        info!("Opened pack store {} with {} chunks", path, store.table.len());
+        store
+    }
+
+    // Ids of all chunks in the pack
+    pub fn chunk_ids(&self) -> Vec<[u8;32]> {
+        self.table.keys().cloned().collect()
+    }
+
+    pub fn contains(&self, id: &[u8;32]) -> bool {
+        self.table.contains_key(id)
+    }
+
+    // Dictionary recorded in the pack header
+    pub fn dictionary(&self) -> Option<Arc<Vec<u8>>> {
+        match &self.compression {
+            ChunkCompression::ZstdDictionary(_, dictionary) => Some(dictionary.clone()),
+            _ => None
+        }
+    }
+
+    // Compressed (and encrypted) chunk as stored
+    pub fn read_raw(&mut self, id: &[u8;32]) -> Vec<u8> {
+        let (offset, length) = match self.table.get(id) {
+            Some(entry) => *entry,
+            None => {
+                panic!("Chunk {} not found in pack store {}", utils::bytes_to_hex(id.to_vec()), self.path);
+            }
+        };
+        let mut buf = vec![0; length as usize];
+        self.file.seek(SeekFrom::Start(offset)).unwrap();
+        self.file.read_exact(&mut buf).expect("Error: Cannot read chunk from pack store");
+        buf
+    }
+
+    // Appends an already compressed (and encrypted) chunk after the last table, a new table
+    // covering it is written when the store is dropped
+    pub fn write_raw(&mut self, id: [u8;32], compressed: &[u8]) {
+        if self.table.contains_key(&id) {
+            return;
+        }
+        if self.read_only {
+            panic!("Pack store {} is opened read-only", self.path);
+        }
+        self.file.seek(SeekFrom::Start(self.end)).unwrap();
+        self.file.write_all(compressed).expect("Error: Cannot write chunk to pack store");
+        self.table.insert(id, (self.end, compressed.len() as u64));
+        self.end += compressed.len() as u64;
+        self.dirty = true;
+    }
+
+    fn write_header(&mut self) {
+        let mut flags = 0;
+        if let ChunkCompression::Uncompressed = self.compression {
+            flags |= PACK_FLAG_UNCOMPRESSED;
+        }
+        if self.encryption.is_some() {
+            flags |= PACK_FLAG_ENCRYPTED;
+        }
+        let dictionary = self.dictionary();
+        let dictionary: &[u8] = dictionary.as_ref().map_or(&[], |d| &d[..]);
+        self.file.seek(SeekFrom::Start(0)).unwrap();
+        utils::write_u64(&mut self.file, PACK_HEADER_MAGIC).expect("Error: Cannot write pack header");
+        utils::write_u64(&mut self.file, flags).expect("Error: Cannot write pack header");
+        utils::write_u64(&mut self.file, dictionary.len() as u64).expect("Error: Cannot write pack header");
+        self.file.write_all(dictionary).expect("Error: Cannot write pack header");
+        self.end = PACK_HEADER_SIZE + dictionary.len() as u64;
+    }
+
+    // Takes compression and dictionary from the header, the encryption given has to match it
+    fn read_header(&mut self) {
+        let size = self.file.metadata().unwrap().len();
+        self.file.seek(SeekFrom::Start(0)).unwrap();
+        if size < PACK_HEADER_SIZE + PACK_TAIL_SIZE || utils::read_u64(&mut self.file) != PACK_HEADER_MAGIC {
+            panic!("{} is not a pack store", self.path);
+        }
+        let flags = utils::read_u64(&mut self.file);
+        let dictionary_size = utils::read_u64(&mut self.file);
+        if dictionary_size > size - PACK_HEADER_SIZE - PACK_TAIL_SIZE {
+            panic!("Pack store {} has a damaged header", self.path);
+        }
+        let encrypted = flags & PACK_FLAG_ENCRYPTED != 0;
+        if encrypted && self.encryption.is_none() {
+            panic!("Pack store {} is encrypted, its key is needed", self.path);
+        } else if !encrypted && self.encryption.is_some() {
+            panic!("Pack store {} is not encrypted", self.path);
+        }
+        let level = match self.compression {
+            ChunkCompression::Zstd(level) | ChunkCompression::ZstdDictionary(level, _) => level,
+            ChunkCompression::Uncompressed => ZSTD_LEVEL_DEFAULT
+        };
+        self.compression = if flags & PACK_FLAG_UNCOMPRESSED != 0 {
+            ChunkCompression::Uncompressed
+        } else if dictionary_size > 0 {
+            let mut dictionary = vec![0; dictionary_size as usize];
+            self.file.read_exact(&mut dictionary).expect("Error: Cannot read pack dictionary");
+            ChunkCompression::ZstdDictionary(level, Arc::new(dictionary))
+        } else {
+            ChunkCompression::Zstd(level)
+        };
+    }
+
+    // Loads the last complete table. Data after it was left by an interrupted write and is cut off.
+    fn read_table(&mut self) {
+        let header_size = PACK_HEADER_SIZE + self.dictionary().map_or(0, |d| d.len() as u64);
+        let size = self.file.metadata().unwrap().len();
+        let (table_offset, count, tail_end) = match find_tail(&mut self.file, header_size, size) {
+            Some(tail) => tail,
+            None => {
+                panic!("Pack store {} has no valid table, it was probably not written completely", self.path);
+            }
+        };
+        if tail_end != size && self.read_only {
+            warn!("Pack store {} was not closed properly, ignoring {} bytes of chunks after its last table", self.path, size - tail_end);
+        } else if tail_end != size {
+            warn!("Pack store {} was not closed properly, dropping {} bytes of chunks after its last table", self.path, size - tail_end);
+            self.file.set_len(tail_end).expect("Error: Cannot truncate pack store");
+        }
+        // Size was checked against the file by find_tail
+        let mut buf = vec![0; (count * PACK_ENTRY_SIZE) as usize];
+        self.file.seek(SeekFrom::Start(table_offset)).unwrap();
+        self.file.read_exact(&mut buf).expect("Error: Cannot read pack table");
+        for entry in buf.chunks(PACK_ENTRY_SIZE as usize) {
+            let mut id: [u8;32] = [0;32];
+            id.copy_from_slice(&entry[..32]);
+            let offset = byteorder::LittleEndian::read_u64(&entry[32..40]);
+            let length = byteorder::LittleEndian::read_u64(&entry[40..48]);
+            if offset < header_size || offset.checked_add(length).is_none_or(|e| e > table_offset) {
+                panic!("Pack store {} has a damaged table", self.path);
+            }
+            self.table.insert(id, (offset, length));
+        }
+        self.end = tail_end;
+    }
+
+    fn write_table(&mut self) {
+        let table_offset = self.end;
+        self.file.seek(SeekFrom::Start(table_offset)).unwrap();
+        let mut entries: Vec<(&[u8;32], &(u64, u64))> = self.table.iter().collect();
+        // Keep the table in data order, makes packs reproducible
+        entries.sort_by_key(|(_, (offset, _))| *offset);
+        let mut buf = Vec::new();
+        for (id, (offset, length)) in entries {
+            buf.extend_from_slice(&id[..]);
+            let mut num = [0; 8];
+            byteorder::LittleEndian::write_u64(&mut num, *offset);
+            buf.extend_from_slice(&num);
+            byteorder::LittleEndian::write_u64(&mut num, *length);
+            buf.extend_from_slice(&num);
+        }
+        self.file.write_all(&buf).expect("Error: Cannot write pack table");
+        utils::write_u64(&mut self.file, table_offset).expect("Error: Cannot write pack tail");
+        utils::write_u64(&mut self.file, self.table.len() as u64).expect("Error: Cannot write pack tail");
+        utils::write_u64(&mut self.file, PACK_TAIL_MAGIC).expect("Error: Cannot write pack tail");
+        self.file.sync_all().expect("Error: Cannot sync pack store");
+        self.end = table_offset + buf.len() as u64 + PACK_TAIL_SIZE;
+        self.dirty = false;
+        debug!("Wrote table with {} chunks to pack store", self.table.len());
+    }
+}
+
+// Finds the last complete table of a pack, returns its offset, entry count and where its tail
+// ends. The tail at the end of the file is tried first, then the pack is searched backwards.
+fn find_tail(file: &mut File, header_size: u64, size: u64) -> Option<(u64, u64, u64)> {
+    if let Some(tail) = read_tail(file, header_size, size) {
+        return Some(tail);
+    }
+    let mut magic = [0; 8];
+    byteorder::LittleEndian::write_u64(&mut magic, PACK_TAIL_MAGIC);
+    let mut block_end = size;
+    while block_end >= header_size + PACK_TAIL_SIZE {
+        let block_start = std::cmp::max(header_size, block_end.saturating_sub(PACK_SCAN_BLOCK_SIZE));
+        let mut buf = vec![0; (block_end - block_start) as usize];
+        file.seek(SeekFrom::Start(block_start)).unwrap();
+        file.read_exact(&mut buf).expect("Error: Cannot read pack store");
+        for i in (0..buf.len().saturating_sub(7)).rev() {
+            if buf[i..i + 8] == magic {
+                if let Some(tail) = read_tail(file, header_size, block_start + i as u64 + 8) {
+                    return Some(tail);
+                }
+            }
+        }
+        if block_start == header_size {
+            break;
+        }
+        // Overlap blocks so a magic number split between two of them is found
+        block_end = block_start + 7;
+    }
+    None
+}
+
+// Table offset and entry count of a tail ending at tail_end, if it is a valid one
+fn read_tail(file: &mut File, header_size: u64, tail_end: u64) -> Option<(u64, u64, u64)> {
+    if tail_end < header_size + PACK_TAIL_SIZE {
+        return None;
+    }
+    file.seek(SeekFrom::Start(tail_end - PACK_TAIL_SIZE)).unwrap();
+    let table_offset = utils::read_u64(file);
+    let count = utils::read_u64(file);
+    if utils::read_u64(file) != PACK_TAIL_MAGIC || table_offset < header_size {
+        return None;
+    }
+    let table_end = count.checked_mul(PACK_ENTRY_SIZE).and_then(|s| s.checked_add(table_offset))?;
+    if table_end + PACK_TAIL_SIZE != tail_end {
+        return None;
+    }
+    Some((table_offset, count, tail_end))
+}
+
+impl Drop for PackStore {
+    fn drop(&mut self) {
+        if self.dirty {
+            self.write_table();
+        }
+    }
+}
+
+impl Store for PackStore {
+    fn create(&self, _path: &str) -> PathBuf {
+        PathBuf::from(&self.path)
+    }
+
+    fn write_item(&mut self, bytes: Vec<u8>) -> [u8;32] {
+        let hash_bytes = self.digest.sum(&bytes);
+
+        if self.contains(&hash_bytes) {
+            self.stats.add_item(bytes.len() as u64);
+        } else {
+            let encoded = encode_chunk(&self.compression, &self.encryption, &hash_bytes, &bytes);
+            self.write_raw(hash_bytes, &encoded);
+            self.stats.add_new_item(bytes.len() as u64, encoded.len() as u64);
+        }
+        hash_bytes
+    }
+
+    fn stats(&self) -> Option<&StoreStats> {
+        Some(&self.stats)
+    }
+
+    fn read_item(&mut self, id: Vec<u8>) -> Vec<u8> {
+        let mut chunk_id: [u8;32] = [0;32];
+        chunk_id.copy_from_slice(&id[..32]);
+        let data = self.read_raw(&chunk_id);
+        decode_chunk(&self.compression, &self.encryption, &chunk_id, &data)
+    }
+}
+
+// Converts a directory store into a pack or a pack back into a directory store,
+// whichever of the two paths ends in .capack is the pack. Chunks are copied as stored,
+// so compressed and encrypted chunks stay that way and the dictionary goes along with them.
+pub fn convert_store(from: &str, to: &str, options: &StoreOptions) {
+    let (min, max, avg) = (0, 0, 0);
+    if is_pack_path(to) && !is_pack_path(from) {
+        let mut local = LocalStore::new(from, min, max, avg);
+        local.encryption = options.encryption();
+        local.check_encryption(true);
+        let dictionary = load_dictionary(from);
+        let compression = options.compression().with_dictionary(dictionary.clone());
+        let mut pack = PackStore::open(to, min, max, avg, compression, options.encryption(), false);
+        if pack.dictionary() != dictionary {
+            panic!("Stores {} and {} use different dictionaries", from, to);
+        }
+        let ids = local.chunk_ids();
+        info!("Packing {} chunks from {} into {}", ids.len(), from, to);
+        for id in ids.iter() {
+            if !pack.contains(id) {
+                let compressed = local.read_raw(id);
+                pack.write_raw(*id, &compressed);
+            }
+        }
+    } else if is_pack_path(from) && !is_pack_path(to) {
+        let mut local = LocalStore::new(to, min, max, avg);
+        let mut pack = PackStore::open(from, min, max, avg, options.compression(), options.encryption(), true);
+        local.encryption = pack.encryption.clone();
+        local.check_encryption(false);
+        if let Some(dictionary) = pack.dictionary() {
+            match load_dictionary(to) {
+                Some(existing) => {
+                    if existing != dictionary {
+                        panic!("Stores {} and {} use different dictionaries", from, to);
+                    }
+                },
+                None => save_dictionary(to, &dictionary)
+            }
+        }
+        let ids = pack.chunk_ids();
+        info!("Unpacking {} chunks from {} into {}", ids.len(), from, to);
+        for id in ids.iter() {
+            let compressed = pack.read_raw(id);
+            local.write_raw(id, &compressed);
+        }
+    } else {
+        panic!("Exactly one of the stores must be a .{} pack", PACK_EXTENSION);
+    }
+}
+
+pub fn is_pack_path(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|e| e == PACK_EXTENSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pack_path(dir: &tempfile::TempDir) -> String {
+        String::from(dir.path().join("store.capack").to_str().unwrap())
+    }
+
+    #[test]
+    fn chunks_added_later_are_appended_after_the_old_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = pack_path(&dir);
+        let options = StoreOptions::default();
+        let first = PackStore::new(&path, 0, 0, 0, &options).write_item(b"first".to_vec());
+        let first_size = std::fs::metadata(&path).unwrap().len();
+
+        let second = PackStore::new(&path, 0, 0, 0, &options).write_item(b"second".to_vec());
+        assert!(std::fs::metadata(&path).unwrap().len() > first_size);
+
+        let mut pack = PackStore::new(&path, 0, 0, 0, &options);
+        assert_eq!(pack.table.len(), 2);
+        assert_eq!(pack.read_item(first.to_vec()), b"first".to_vec());
+        assert_eq!(pack.read_item(second.to_vec()), b"second".to_vec());
+    }
+
+    #[test]
+    fn interrupted_write_keeps_the_last_complete_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = pack_path(&dir);
+        let options = StoreOptions::default();
+        let first = PackStore::new(&path, 0, 0, 0, &options).write_item(b"first".to_vec());
+        let complete_size = std::fs::metadata(&path).unwrap().len();
+
+        // Killed before the store was dropped, the new table was never written
+        let mut pack = PackStore::new(&path, 0, 0, 0, &options);
+        pack.write_item(b"lost".to_vec());
+        std::mem::forget(pack);
+        assert!(std::fs::metadata(&path).unwrap().len() > complete_size);
+
+        let mut pack = PackStore::new(&path, 0, 0, 0, &options);
+        assert_eq!(pack.chunk_ids(), vec![first]);
+        assert_eq!(pack.read_item(first.to_vec()), b"first".to_vec());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), complete_size);
+    }
+
+    #[test]
+    fn damaged_tail_is_not_trusted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = pack_path(&dir);
+        let options = StoreOptions::default();
+        let first = PackStore::new(&path, 0, 0, 0, &options).write_item(b"first".to_vec());
+
+        // A tail claiming a table far bigger than the file
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        utils::write_u64(&mut file, PACK_HEADER_SIZE).unwrap();
+        utils::write_u64(&mut file, u64::MAX / 8).unwrap();
+        utils::write_u64(&mut file, PACK_TAIL_MAGIC).unwrap();
+        drop(file);
+
+        let pack = PackStore::new(&path, 0, 0, 0, &options);
+        assert_eq!(pack.chunk_ids(), vec![first]);
+    }
+
+    #[test]
+    fn read_only_pack_is_left_as_it_is() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = pack_path(&dir);
+        let first = PackStore::new(&path, 0, 0, 0, &StoreOptions::default()).write_item(b"first".to_vec());
+        // Chunks of an interrupted write after the table stay where they are
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"partly written chunk").unwrap();
+        drop(file);
+        let before = std::fs::read(&path).unwrap();
+        let mut permissions = std::fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&path, permissions).unwrap();
+
+        let read_only = StoreOptions { read_only: true, ..StoreOptions::default() };
+        let mut pack = PackStore::new(&path, 0, 0, 0, &read_only);
+        assert_eq!(pack.read_item(first.to_vec()), b"first".to_vec());
+        drop(pack);
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+    }
+
+    #[test]
+    fn missing_read_only_pack_is_not_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = pack_path(&dir);
+        let read_only = StoreOptions { read_only: true, ..StoreOptions::default() };
+        let e = std::panic::catch_unwind(|| PackStore::new(&path, 0, 0, 0, &read_only).table.len()).unwrap_err();
+        assert!(e.downcast_ref::<String>().unwrap().contains("does not exist"));
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    fn compression_is_taken_from_the_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = pack_path(&dir);
+        let uncompressed = StoreOptions { uncompressed: true, ..StoreOptions::default() };
+        let id = PackStore::new(&path, 0, 0, 0, &uncompressed).write_item(b"plain chunk".to_vec());
+
+        let mut pack = PackStore::new(&path, 0, 0, 0, &StoreOptions::default());
+        assert!(matches!(pack.compression, ChunkCompression::Uncompressed));
+        assert_eq!(pack.read_raw(&id), b"plain chunk".to_vec());
+        assert_eq!(pack.read_item(id.to_vec()), b"plain chunk".to_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "is encrypted")]
+    fn encrypted_pack_needs_its_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = pack_path(&dir);
+        let key_path = dir.path().join("key");
+        std::fs::write(&key_path, [7u8; 32]).unwrap();
+        let encrypted = StoreOptions { encryption_key: Some(String::from(key_path.to_str().unwrap())), ..StoreOptions::default() };
+        PackStore::new(&path, 0, 0, 0, &encrypted).write_item(b"secret".to_vec());
+        PackStore::new(&path, 0, 0, 0, &StoreOptions::default());
+    }
+
+    #[test]
+    fn convert_store_carries_the_dictionary() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = String::from(dir.path().join("store").to_str().unwrap());
+        let unpacked = String::from(dir.path().join("unpacked").to_str().unwrap());
+        let path = pack_path(&dir);
+        let dictionary: Vec<u8> = b"chunk contents shared by many chunks ".iter().cycle().take(4096).cloned().collect();
+        std::fs::create_dir_all(&folder).unwrap();
+        save_dictionary(&folder, &dictionary);
+        let mut local = LocalStore::new(&folder, 0, 0, 0);
+        local.compression = ChunkCompression::Zstd(ZSTD_LEVEL_DEFAULT).with_dictionary(load_dictionary(&folder));
+        let id = local.write_item(b"chunk contents shared by many chunks, and some more".to_vec());
+
+        let options = StoreOptions::default();
+        convert_store(&folder, &path, &options);
+        let mut pack = PackStore::new(&path, 0, 0, 0, &options);
+        assert_eq!(pack.dictionary(), Some(Arc::new(dictionary.clone())));
+        assert_eq!(pack.read_item(id.to_vec()), local.read_item(id.to_vec()));
+        drop(pack);
+
+        convert_store(&path, &unpacked, &options);
+        assert_eq!(load_dictionary(&unpacked), Some(Arc::new(dictionary)));
+        assert_eq!(LocalStore::new(&unpacked, 0, 0, 0).read_raw(&id), local.read_raw(&id));
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crypto::sha2::Sha256;
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use hyper::{Method, StatusCode};
+use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION, HOST, IF_NONE_MATCH};
+use url::Url;
+use log::{info, debug};
+
+use crate::digest::ChunkDigest;
+use super::{Store, StoreStats, StoreOptions, RemoteHTTPClient, ChunkCompression, ChunkCipher, encode_chunk, decode_chunk};
+use super::dictionary::{self, DICTIONARY_FILE_NAME};
+use super::crypt::{self, ENCRYPTION_MARKER, ENCRYPTION_MARKER_FILE_NAME};
+
+const S3_DEFAULT_REGION: &str = "us-east-1";
+
+// S3Store Chunk store inside an s3 compatible bucket, addressed as s3://bucket/prefix
+pub struct S3Store {
+    pub bucket: String,
+    pub prefix: String,
+    pub endpoint: Url,
+    pub region: String,
+    pub stats: StoreStats,
+    pub client: RemoteHTTPClient,
+    pub compression: ChunkCompression,
+    pub encryption: Option<ChunkCipher>,
+    pub digest: ChunkDigest,
+    credentials: S3Credentials
+}
+
+impl S3Store {
+    pub fn new(url: &Url, min: u64, max: u64, avg: u64, options: &StoreOptions) -> S3Store {
+        let bucket = match url.host_str() {
+            Some(b) => String::from(b),
+            None => {
+                panic!("s3 store url needs a bucket, s3://bucket/prefix");
+            }
+        };
+        let region = options.s3_region.clone()
+            .or(std::env::var("AWS_REGION").ok())
+            .unwrap_or(String::from(S3_DEFAULT_REGION));
+        let endpoint = options.s3_endpoint.clone()
+            .unwrap_or(format!("https://s3.{}.amazonaws.com", region));
+        let endpoint = match Url::parse(&endpoint) {
+            Ok(e) => e,
+            Err(e) => {
+                panic!("Invalid s3 endpoint {}, {:?}", endpoint, e);
+            }
+        };
+        let credentials = S3Credentials::find(options.s3_credentials_file.as_deref());
+        let mut store = S3Store {
+            bucket,
+            prefix: String::from(url.path().trim_matches('/')),
+            endpoint,
+            region,
+            stats: StoreStats::new(min, max, avg),
+            client: RemoteHTTPClient::new(options),
+            compression: options.compression(),
+            encryption: options.encryption(),
+            digest: options.digest,
+            credentials
+        };
+        store.check_encryption(options.read_only);
+        if let ChunkCompression::Zstd(_) = store.compression {
+            let url = format!("s3://{}/{}", store.bucket, store.store_key(DICTIONARY_FILE_NAME));
+            let dictionary = dictionary::shared_dictionary(options, &url, || store.load_dictionary());
+            store.compression = store.compression.with_dictionary(dictionary);
+        }
+        store
+    }
+
+    // Key of a file below the store prefix
+    fn store_key(&self, file_path: &str) -> String {
+        if self.prefix.is_empty() {
+            String::from(file_path)
+        } else {
+            format!("{}/{}", self.prefix, file_path)
+        }
+    }
+
+    fn chunk_key(&self, chunk_name: &str) -> String {
+        let (sub_dir_name,_) = chunk_name.split_at(4);
+        self.store_key(&format!("{}/{}", sub_dir_name, self.compression.file_name(chunk_name)))
+    }
+
+    // Checks the key of the store against its encryption marker, marks a new store encrypted
+    fn check_encryption(&self, read_only: bool) {
+        let key = self.store_key(ENCRYPTION_MARKER_FILE_NAME);
+        let (status, _) = self.send(Method::GET, &key, Vec::new());
+        let marked = if status == StatusCode::NOT_FOUND || status == StatusCode::FORBIDDEN {
+            false
+        } else if status.is_success() {
+            true
+        } else {
+            panic!("Could not read encryption marker {} from s3 store, {:?}", key, status);
+        };
+        // Keys of a bucket may not be listable, a store which is written to is taken to be new
+        let path = format!("s3://{}/{}", self.bucket, self.prefix);
+        if crypt::check_encryption(&path, marked, &self.encryption, read_only) {
+            let mut headers = HeaderMap::new();
+            headers.insert(IF_NONE_MATCH, HeaderValue::from_static("*"));
+            let (status, body) = self.send_with_headers(Method::PUT, &key, headers, ENCRYPTION_MARKER.to_vec());
+            if !status.is_success() && status != StatusCode::PRECONDITION_FAILED {
+                panic!("Could not write encryption marker {} to s3 store, {:?} {}", key, status, String::from_utf8_lossy(&body));
+            }
+        }
+    }
+
+    // Dictionary of the store, None if the bucket has none. S3 answers 403 rather than 404 for
+    // missing keys when the credentials may not list the bucket.
+    fn load_dictionary(&self) -> Option<Arc<Vec<u8>>> {
+        let key = self.store_key(DICTIONARY_FILE_NAME);
+        let (status, body) = self.send(Method::GET, &key, Vec::new());
+        if status == StatusCode::NOT_FOUND || status == StatusCode::FORBIDDEN {
+            debug!("No dictionary {} in s3 store, {:?}", key, status);
+            None
+        } else if status.is_success() {
+            info!("Using dictionary {}", key);
+            Some(Arc::new(body.to_vec()))
+        } else {
+            panic!("Could not read dictionary {} from s3 store, {:?}", key, status);
+        }
+    }
+
+    fn send(&self, method: Method, key: &str, body: Vec<u8>) -> (StatusCode, bytes::Bytes) {
+        self.send_with_headers(method, key, HeaderMap::new(), body)
+    }
+
+    // Sends a SigV4 signed path style request for the given key, extra headers are not signed
+    fn send_with_headers(&self, method: Method, key: &str, extra: HeaderMap, body: Vec<u8>) -> (StatusCode, bytes::Bytes) {
+        let canonical_uri = uri_encode(&format!("/{}/{}", self.bucket, key));
+        let host = match self.endpoint.port() {
+            Some(port) => format!("{}:{}", self.endpoint.host_str().unwrap_or(""), port),
+            None => String::from(self.endpoint.host_str().unwrap_or(""))
+        };
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = sha256_hex(&body);
+
+        // Header names are already lower case and sorted as required for signing
+        let mut signed: Vec<(&str, String)> = vec![
+            ("host", host.clone()),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone())
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            signed.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical_headers: String = signed.iter().map(|(n, v)| format!("{}:{}\n", n, v.trim())).collect();
+        let signed_headers = signed.iter().map(|(n, _)| *n).collect::<Vec<&str>>().join(";");
+        let canonical_request = format!("{}\n{}\n\n{}\n{}\n{}",
+            method.as_str(), canonical_uri, canonical_headers, signed_headers, payload_hash);
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date, scope, sha256_hex(canonical_request.as_bytes()));
+        let mut key_bytes = hmac_sha256(format!("AWS4{}", self.credentials.secret_key).as_bytes(), date.as_bytes());
+        key_bytes = hmac_sha256(&key_bytes, self.region.as_bytes());
+        key_bytes = hmac_sha256(&key_bytes, b"s3");
+        key_bytes = hmac_sha256(&key_bytes, b"aws4_request");
+        let signature = to_hex(&hmac_sha256(&key_bytes, string_to_sign.as_bytes()));
+        let authorization = format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.credentials.access_key, scope, signed_headers, signature);
+
+        let mut headers = extra;
+        for (name, value) in signed.iter() {
+            if *name == "host" {
+                headers.insert(HOST, HeaderValue::from_str(value).unwrap());
+            } else {
+                headers.insert(*name, HeaderValue::from_str(value).expect("Error: Invalid s3 header value"));
+            }
+        }
+        let mut auth_value = HeaderValue::from_str(&authorization).unwrap();
+        auth_value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, auth_value);
+
+        let mut url = self.endpoint.clone();
+        url.set_path(&canonical_uri);
+        debug!("s3 {} {}", method, url);
+        self.client.request_with_headers(method, url.as_str(), headers, body)
+    }
+
+}
+
+impl Store for S3Store {
+    fn create(&self, path: &str) -> PathBuf {
+        // Buckets are managed outside, prefixes need no creation in s3
+        Path::new(path).to_path_buf()
+    }
+
+    // Chunks are written with a conditional PUT, which the store rejects for chunks it already
+    // has, saving a HEAD request per chunk
+    fn write_item(&mut self, bytes: Vec<u8>) -> [u8;32] {
+        let hash_bytes = self.digest.sum(&bytes);
+        let key = self.chunk_key(&to_hex(&hash_bytes));
+        let encoded = encode_chunk(&self.compression, &self.encryption, &hash_bytes, &bytes);
+        let stored_size = encoded.len() as u64;
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("*"));
+        let (status, body) = self.send_with_headers(Method::PUT, &key, headers, encoded);
+        if status == StatusCode::PRECONDITION_FAILED {
+            self.stats.add_item(bytes.len() as u64);
+        } else if status.is_success() {
+            self.stats.add_new_item(bytes.len() as u64, stored_size);
+        } else {
+            panic!("Could not write {} to s3 store, {:?} {}", key, status, String::from_utf8_lossy(&body));
+        }
+        hash_bytes
+    }
+
+    fn stats(&self) -> Option<&StoreStats> {
+        Some(&self.stats)
+    }
+
+    fn read_item(&mut self, id: Vec<u8>) -> Vec<u8> {
+        use rustc_serialize::hex::ToHex;
+        let key = self.chunk_key(&id[..].to_hex());
+        info!("s3 key, {}", key);
+        let (status, data) = self.send(Method::GET, &key, Vec::new());
+        if ! status.is_success() {
+            panic!("Could not read {} from s3 store, {:?}", key, status);
+        }
+        let mut chunk_id: [u8;32] = [0;32];
+        chunk_id.copy_from_slice(&id[..32]);
+        decode_chunk(&self.compression, &self.encryption, &chunk_id, &data[..])
+    }
+}
+
+// S3Credentials Access key of an s3 store. Taken from a credentials file given on the command
+// line, the AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY variables or the shared AWS credentials
+// file, in that order, never from arguments so they stay out of process listings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct S3Credentials {
+    pub access_key: String,
+    pub secret_key: String,
+    pub session_token: Option<String>
+}
+
+impl S3Credentials {
+    pub fn find(credentials_file: Option<&str>) -> S3Credentials {
+        let profile = std::env::var("AWS_PROFILE").unwrap_or(String::from("default"));
+        if let Some(path) = credentials_file {
+            return S3Credentials::from_file(path, &profile);
+        }
+        if let (Ok(access_key), Ok(secret_key)) = (std::env::var("AWS_ACCESS_KEY_ID"), std::env::var("AWS_SECRET_ACCESS_KEY")) {
+            return S3Credentials {
+                access_key,
+                secret_key,
+                session_token: std::env::var("AWS_SESSION_TOKEN").ok()
+            };
+        }
+        let shared = std::env::var("AWS_SHARED_CREDENTIALS_FILE").ok()
+            .or(std::env::var("HOME").ok().map(|home| format!("{}/.aws/credentials", home)));
+        match shared {
+            Some(path) if Path::new(&path).exists() => S3Credentials::from_file(&path, &profile),
+            _ => {
+                panic!("No s3 credentials, set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY or use --s3-credentials-file");
+            }
+        }
+    }
+
+    // Reads a profile of a credentials file in the format of ~/.aws/credentials
+    pub fn from_file(path: &str, profile: &str) -> S3Credentials {
+        let content = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) => {
+                panic!("Could not read s3 credentials file {}, {:?}", path, e);
+            }
+        };
+        match S3Credentials::parse(&content, profile) {
+            Some(credentials) => credentials,
+            None => {
+                panic!("No aws_access_key_id and aws_secret_access_key for profile {} in {}", profile, path);
+            }
+        }
+    }
+
+    fn parse(content: &str, profile: &str) -> Option<S3Credentials> {
+        let mut section = String::new();
+        let mut values: HashMap<String, String> = HashMap::new();
+        for line in content.lines().map(|l| l.trim()) {
+            if line.starts_with('[') && line.ends_with(']') {
+                section = String::from(line[1..line.len() - 1].trim());
+            } else if section == profile {
+                if let Some(i) = line.find('=') {
+                    values.insert(String::from(line[..i].trim()), String::from(line[i + 1..].trim()));
+                }
+            }
+        }
+        Some(S3Credentials {
+            access_key: values.remove("aws_access_key_id")?,
+            secret_key: values.remove("aws_secret_access_key")?,
+            session_token: values.remove("aws_session_token")
+        })
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result_str()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    hmac.input(data);
+    hmac.result().code().to_vec()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    use rustc_serialize::hex::ToHex;
+    bytes.to_hex()
+}
+
+// Percent encodes everything but '/' and the unreserved characters of RFC 3986, as SigV4 expects
+fn uri_encode(input: &str) -> String {
+    input.bytes().map(|b| {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            b'/' => String::from("/"),
+            _ => format!("%{:02X}", b)
+        }
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_server::TestServer;
+
+    fn credentials_file(dir: &Path) -> String {
+        let path = dir.join("credentials");
+        std::fs::write(&path, "[other]\naws_access_key_id = wrong\naws_secret_access_key = wrong\n\n\
+            [default]\naws_access_key_id = AKIDEXAMPLE\naws_secret_access_key = wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY\n").unwrap();
+        String::from(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn credentials_are_read_from_the_profile() {
+        let content = "[default]\naws_access_key_id=A\naws_secret_access_key = B\n[ci]\naws_access_key_id = C\naws_secret_access_key = D\naws_session_token = E\n";
+        assert_eq!(S3Credentials::parse(content, "default"), Some(S3Credentials {
+            access_key: String::from("A"), secret_key: String::from("B"), session_token: None
+        }));
+        assert_eq!(S3Credentials::parse(content, "ci"), Some(S3Credentials {
+            access_key: String::from("C"), secret_key: String::from("D"), session_token: Some(String::from("E"))
+        }));
+        assert_eq!(S3Credentials::parse(content, "missing"), None);
+    }
+
+    #[test]
+    fn chunks_are_put_once_without_head_requests() {
+        let server = TestServer::start();
+        let dir = tempfile::tempdir().unwrap();
+        let options = StoreOptions {
+            s3_endpoint: Some(server.url.clone()),
+            s3_region: Some(String::from("eu-west-1")),
+            s3_credentials_file: Some(credentials_file(dir.path())),
+            ..StoreOptions::default()
+        };
+        let mut store = S3Store::new(&Url::parse("s3://bucket/some/prefix").unwrap(), 0, 0, 0, &options);
+        let id = store.write_item(b"s3 chunk".to_vec());
+        store.write_item(b"s3 chunk".to_vec());
+        assert_eq!(store.read_item(id.to_vec()), b"s3 chunk".to_vec());
+
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.count, stats.new_chunks_count), (2, 1));
+        let requests = server.requests();
+        assert_eq!(requests.iter().map(|r| r.method.as_str()).collect::<Vec<_>>(), vec!["GET", "GET", "PUT", "PUT", "GET"]);
+        assert_eq!(requests[0].path, "/bucket/some/prefix/encryption.marker");
+        assert_eq!(requests[1].path, "/bucket/some/prefix/dictionary.zdict");
+        let put = &requests[2];
+        assert_eq!(put.path, format!("/bucket/{}", store.chunk_key(&to_hex(&id))));
+        assert!(put.path.starts_with("/bucket/some/prefix/"));
+        assert_eq!(put.headers.get("if-none-match").map(|v| v.as_str()), Some("*"));
+        assert_eq!(put.headers.get("x-amz-content-sha256"), Some(&sha256_hex(&put.body)));
+        let authorization = put.headers.get("authorization").unwrap();
+        assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"), "{}", authorization);
+        assert!(authorization.contains("/eu-west-1/s3/aws4_request"));
+    }
+
+    #[test]
+    fn forbidden_dictionary_means_none() {
+        let server = TestServer::start();
+        server.forbidden.lock().unwrap().insert(String::from("/bucket/prefix/dictionary.zdict"));
+        let dir = tempfile::tempdir().unwrap();
+        let options = StoreOptions {
+            s3_endpoint: Some(server.url.clone()),
+            s3_credentials_file: Some(credentials_file(dir.path())),
+            ..StoreOptions::default()
+        };
+        let mut store = S3Store::new(&Url::parse("s3://bucket/prefix").unwrap(), 0, 0, 0, &options);
+        match store.compression {
+            ChunkCompression::Zstd(_) => (),
+            _ => panic!("s3 store without a dictionary should compress without one")
+        }
+        let id = store.write_item(b"chunk without dictionary".to_vec());
+        assert_eq!(store.read_item(id.to_vec()), b"chunk without dictionary".to_vec());
+    }
+
+    // Runs against a real s3 compatible store at DESYNC_TEST_S3_URL (s3://bucket/prefix) and
+    // DESYNC_TEST_S3_ENDPOINT, e.g. for MinIO:
+    //   DESYNC_TEST_S3_URL=s3://test/desync DESYNC_TEST_S3_ENDPOINT=http://localhost:9000 \
+    //   AWS_ACCESS_KEY_ID=minioadmin AWS_SECRET_ACCESS_KEY=minioadmin cargo test minio -- --ignored
+    #[test]
+    #[ignore = "needs DESYNC_TEST_S3_URL and DESYNC_TEST_S3_ENDPOINT of a running s3 store"]
+    fn minio_round_trip() {
+        let url = std::env::var("DESYNC_TEST_S3_URL").expect("Error: DESYNC_TEST_S3_URL not set");
+        let endpoint = std::env::var("DESYNC_TEST_S3_ENDPOINT").expect("Error: DESYNC_TEST_S3_ENDPOINT not set");
+        let options = StoreOptions { s3_endpoint: Some(endpoint), ..StoreOptions::default() };
+        let mut store = S3Store::new(&Url::parse(&url).unwrap(), 0, 0, 0, &options);
+        let data: Vec<u8> = format!("minio chunk {:?}", std::time::SystemTime::now()).into_bytes();
+        let id = store.write_item(data.clone());
+        assert_eq!(store.write_item(data.clone()), id);
+        assert_eq!(store.read_item(id.to_vec()), data);
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.count, stats.new_chunks_count), (2, 1));
+    }
+}
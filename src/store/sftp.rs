@@ -0,0 +1,284 @@
+use std::path::{Path, PathBuf};
+use std::net::TcpStream;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use ssh2::{Session, Sftp, CheckResult, KnownHostFileKind, ErrorCode, RenameFlags};
+use url::Url;
+use log::{info, warn};
+
+use crate::digest::ChunkDigest;
+use super::{Store, StoreStats, StoreOptions, ChunkCompression, ChunkCipher, encode_chunk, decode_chunk, temp_chunk_path};
+use super::dictionary::DICTIONARY_FILE_NAME;
+
+// Status code of libssh2 for missing remote files
+const SFTP_NO_SUCH_FILE: i32 = 2;
+
+// SFTPStore Chunk store on a host only reachable over ssh, addressed as ssh://user@host:port/path
+pub struct SFTPStore {
+    pub path: String,
+    pub stats: StoreStats,
+    pub sftp: Sftp,
+    pub compression: ChunkCompression,
+    pub encryption: Option<ChunkCipher>,
+    pub digest: ChunkDigest
+}
+
+impl SFTPStore {
+    pub fn new(url: &Url, min: u64, max: u64, avg: u64, options: &StoreOptions) -> SFTPStore {
+        let host = match url.host_str() {
+            Some(h) => h,
+            None => {
+                panic!("ssh store url needs a host, ssh://user@host/path");
+            }
+        };
+        let port = url.port().unwrap_or(22);
+        let user = if url.username().is_empty() {
+            std::env::var("USER").expect("Error: No user in ssh store url and USER is not set")
+        } else {
+            String::from(url.username())
+        };
+
+        let tcp = match TcpStream::connect((host, port)) {
+            Ok(tcp) => tcp,
+            Err(e) => {
+                panic!("Could not connect to ssh store {}:{}, {:?}", host, port, e);
+            }
+        };
+        let mut session = Session::new().expect("Error: Cannot create ssh session");
+        session.set_tcp_stream(tcp);
+        if let Err(e) = session.handshake() {
+            panic!("ssh handshake with {} failed, {:?}", host, e);
+        }
+        check_host_key(&session, host, port, options.insecure_skip_verify);
+
+        // Private key given on command line wins over keys held by the ssh agent
+        let auth = match &options.ssh_key {
+            Some(key) => session.userauth_pubkey_file(&user, None, Path::new(key), None),
+            None => session.userauth_agent(&user)
+        };
+        if auth.is_err() || !session.authenticated() {
+            panic!("ssh authentication as {} on {} failed, {:?}", user, host, auth.err());
+        }
+
+        let sftp = match session.sftp() {
+            Ok(sftp) => sftp,
+            Err(e) => {
+                panic!("Could not start sftp on {}, {:?}", host, e);
+            }
+        };
+        let mut store = SFTPStore {
+            path: String::from(url.path()),
+            stats: StoreStats::new(min, max, avg),
+            sftp,
+            compression: options.compression(),
+            encryption: options.encryption(),
+            digest: options.digest
+        };
+        if let ChunkCompression::Zstd(_) = store.compression {
+            let dictionary = store.load_dictionary();
+            store.compression = store.compression.with_dictionary(dictionary);
+        }
+        store
+    }
+
+    // Dictionary of the store, None if it has none
+    fn load_dictionary(&self) -> Option<Arc<Vec<u8>>> {
+        let path = Path::new(&self.path).join(DICTIONARY_FILE_NAME);
+        if !self.exists(&path) {
+            return None;
+        }
+        match self.sftp.open(&path) {
+            Ok(mut file) => {
+                let mut dictionary = Vec::new();
+                file.read_to_end(&mut dictionary).expect("Error: Cannot read dictionary from ssh store");
+                info!("Using dictionary {:?}", path);
+                Some(Arc::new(dictionary))
+            },
+            Err(e) => {
+                panic!("Could not read dictionary {:?}, {:?}", path, e);
+            }
+        }
+    }
+
+    fn chunk_path(&self, chunk_name: &str) -> PathBuf {
+        let (sub_dir_name,_) = chunk_name.split_at(4);
+        let mut full_path = PathBuf::from(&self.path);
+        full_path.push(sub_dir_name);
+        full_path.push(self.compression.file_name(chunk_name));
+        full_path
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        match self.sftp.stat(path) {
+            Ok(_) => true,
+            Err(e) => {
+                if let ErrorCode::SFTP(SFTP_NO_SUCH_FILE) = e.code() {
+                    false
+                } else {
+                    panic!("Could not look up {:?} in ssh store, {:?}", path, e);
+                }
+            }
+        }
+    }
+
+    // Creates the directory and any missing parents, like mkdir -p
+    fn mkdir(&self, path: &Path) {
+        let missing: Vec<&Path> = path.ancestors()
+            .filter(|p| !p.as_os_str().is_empty())
+            .take_while(|p| !self.exists(p))
+            .collect();
+        for dir in missing.iter().rev() {
+            if let Err(e) = self.sftp.mkdir(dir, 0o755) {
+                // Another writer may have created it in the meantime
+                if !self.exists(dir) {
+                    panic!("Could not create {:?} in ssh store, {:?}", dir, e);
+                }
+            }
+        }
+    }
+
+    // Writes the chunk under a temporary name and renames it into place, so an interrupted
+    // upload never leaves a truncated chunk behind. Returns false if the chunk was already there.
+    fn write_chunk_file(&self, chunk_path: &Path, encoded: &[u8]) -> bool {
+        let temp_path = temp_chunk_path(chunk_path);
+        match self.sftp.create(&temp_path) {
+            Ok(mut f) => {
+                f.write_all(encoded).expect("Error: Cannot write compressed data to ssh store");
+                f.flush().expect("Error: Cannot flush chunk to ssh store");
+                f.fsync().ok();
+            },
+            Err(e) => {
+                panic!("Could not create file to write chunk, {:?}", e)
+            }
+        }
+        // Servers speaking sftp v3 refuse to rename over an existing file, in that case another
+        // writer stored the same chunk first
+        match self.sftp.rename(&temp_path, chunk_path, Some(RenameFlags::ATOMIC | RenameFlags::NATIVE)) {
+            Ok(()) => true,
+            Err(e) => {
+                let _ = self.sftp.unlink(&temp_path);
+                if self.exists(chunk_path) {
+                    false
+                } else {
+                    panic!("Could not move chunk into place {:?}, {:?}", chunk_path, e);
+                }
+            }
+        }
+    }
+}
+
+// Verifies the host key against ~/.ssh/known_hosts the way ssh does in batch mode
+fn check_host_key(session: &Session, host: &str, port: u16, insecure_skip_verify: bool) {
+    if insecure_skip_verify {
+        warn!("Skipping host key verification for ssh store {}", host);
+        return;
+    }
+    let mut known_hosts = session.known_hosts().expect("Error: Cannot load known hosts");
+    let known_hosts_file = match std::env::var("HOME") {
+        Ok(home) => Path::new(&home).join(".ssh").join("known_hosts"),
+        Err(_) => {
+            panic!("HOME is not set, cannot find known_hosts for ssh store");
+        }
+    };
+    if let Err(e) = known_hosts.read_file(&known_hosts_file, KnownHostFileKind::OpenSSH) {
+        panic!("Could not read {:?}, {:?}", known_hosts_file, e);
+    }
+    let (key, _) = session.host_key().expect("Error: ssh server sent no host key");
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => {},
+        CheckResult::NotFound => {
+            panic!("Host key of {} is not in {:?}", host, known_hosts_file);
+        },
+        CheckResult::Mismatch => {
+            panic!("Host key of {} does not match {:?}", host, known_hosts_file);
+        },
+        CheckResult::Failure => {
+            panic!("Could not check host key of {}", host);
+        }
+    }
+}
+
+impl Store for SFTPStore {
+    fn create(&self, path: &str) -> PathBuf {
+        let final_path = Path::new(&self.path).join(path);
+        self.mkdir(&final_path);
+        final_path
+    }
+
+    fn write_item(&mut self, bytes: Vec<u8>) -> [u8;32] {
+        use rustc_serialize::hex::ToHex;
+        let hash_bytes = self.digest.sum(&bytes);
+        let hash_value = hash_bytes[..].to_hex();
+        let chunk_path = self.chunk_path(&hash_value);
+
+        if self.exists(&chunk_path) {
+            self.stats.add_item(bytes.len() as u64);
+        } else {
+            let (sub_dir_name,_) = hash_value.split_at(4);
+            self.create(sub_dir_name);
+            let encoded = encode_chunk(&self.compression, &self.encryption, &hash_bytes, &bytes);
+            if self.write_chunk_file(&chunk_path, &encoded) {
+                self.stats.add_new_item(bytes.len() as u64, encoded.len() as u64);
+            } else {
+                self.stats.add_item(bytes.len() as u64);
+            }
+        }
+        hash_bytes
+    }
+
+    fn stats(&self) -> Option<&StoreStats> {
+        Some(&self.stats)
+    }
+
+    fn read_item(&mut self, id: Vec<u8>) -> Vec<u8> {
+        use rustc_serialize::hex::ToHex;
+        let chunk_path = self.chunk_path(&id[..].to_hex());
+        info!("ssh path, {:?}", chunk_path);
+        match self.sftp.open(&chunk_path) {
+            Ok(mut file) => {
+                let mut data = Vec::new();
+                file.read_to_end(&mut data).expect("Error: Cannot read chunk from ssh store");
+                let mut chunk_id: [u8;32] = [0;32];
+                chunk_id.copy_from_slice(&id[..32]);
+                decode_chunk(&self.compression, &self.encryption, &chunk_id, &data)
+            },
+            Err(e) => {
+                panic!("Could not open file to read, {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs against a real ssh server at DESYNC_TEST_SFTP_URL, e.g.
+    //   DESYNC_TEST_SFTP_URL=ssh://user@localhost/tmp/desync-test cargo test sftp -- --ignored
+    // The key is taken from DESYNC_TEST_SFTP_KEY or the ssh agent. The store is created in
+    // nested directories below the url path that do not exist yet.
+    #[test]
+    #[ignore = "needs DESYNC_TEST_SFTP_URL of a running ssh server"]
+    fn sftp_round_trip_in_new_nested_directory() {
+        let url = std::env::var("DESYNC_TEST_SFTP_URL").expect("Error: DESYNC_TEST_SFTP_URL not set");
+        let mut url = Url::parse(&url).unwrap();
+        let nested = format!("{}/run-{}/a/b", url.path().trim_end_matches('/'), std::process::id());
+        url.set_path(&nested);
+        let options = StoreOptions {
+            ssh_key: std::env::var("DESYNC_TEST_SFTP_KEY").ok(),
+            ..StoreOptions::default()
+        };
+        let mut store = SFTPStore::new(&url, 0, 0, 0, &options);
+        store.create("");
+        let id = store.write_item(b"sftp chunk".to_vec());
+        assert_eq!(store.write_item(b"sftp chunk".to_vec()), id);
+        assert_eq!(store.read_item(id.to_vec()), b"sftp chunk".to_vec());
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.count, stats.new_chunks_count), (2, 1));
+
+        // Only the final chunk file is left, no temporary upload
+        let chunk_path = store.chunk_path(&rustc_serialize::hex::ToHex::to_hex(&id[..]));
+        let names: Vec<PathBuf> = store.sftp.readdir(chunk_path.parent().unwrap()).unwrap().into_iter().map(|(p, _)| p).collect();
+        assert_eq!(names, vec![chunk_path]);
+    }
+}
@@ -0,0 +1,102 @@
+// Minimal HTTP/1.1 object server for testing remote stores without a network. Objects are kept
+// in memory by path, PUT honours If-None-Match: * and every request is recorded. Forbidden paths
+// are answered with 403, as s3 does for missing keys without the right to list the bucket.
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    // Header names are lower case
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>
+}
+
+pub struct TestServer {
+    pub url: String,
+    pub objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    pub forbidden: Arc<Mutex<HashSet<String>>>,
+    pub requests: Arc<Mutex<Vec<RecordedRequest>>>
+}
+
+impl TestServer {
+    pub fn start() -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let objects: Arc<Mutex<HashMap<String, Vec<u8>>>> = Arc::new(Mutex::new(HashMap::new()));
+        let forbidden: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let (server_objects, server_forbidden, server_requests) = (objects.clone(), forbidden.clone(), requests.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => continue
+                };
+                let request = match read_request(&mut stream) {
+                    Some(r) => r,
+                    None => continue
+                };
+                let (status, body) = {
+                    let mut objects = server_objects.lock().unwrap();
+                    match request.method.as_str() {
+                        _ if server_forbidden.lock().unwrap().contains(&request.path) => ("403 Forbidden", Vec::new()),
+                        "GET" | "HEAD" => match objects.get(&request.path) {
+                            Some(data) => ("200 OK", data.clone()),
+                            None => ("404 Not Found", Vec::new())
+                        },
+                        "PUT" => {
+                            if request.headers.get("if-none-match").is_some_and(|v| v == "*") && objects.contains_key(&request.path) {
+                                ("412 Precondition Failed", Vec::new())
+                            } else {
+                                objects.insert(request.path.clone(), request.body.clone());
+                                ("200 OK", Vec::new())
+                            }
+                        },
+                        _ => ("405 Method Not Allowed", Vec::new())
+                    }
+                };
+                let send_body = request.method != "HEAD";
+                server_requests.lock().unwrap().push(request);
+                let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+                if send_body {
+                    let _ = stream.write_all(&body);
+                }
+            }
+        });
+        TestServer { url, objects, forbidden, requests }
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn read_request<R: Read>(stream: R) -> Option<RecordedRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = String::from(parts.next()?);
+    let path = String::from(parts.next()?);
+    let mut headers = HashMap::new();
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(i) = header.find(':') {
+            headers.insert(header[..i].trim().to_lowercase(), String::from(header[i + 1..].trim()));
+        }
+    }
+    let length = headers.get("content-length").and_then(|l| l.parse::<usize>().ok()).unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    Some(RecordedRequest { method, path, headers, body })
+}
@@ -1,6 +1,6 @@
 use std::io::{Write, Read};
 use byteorder::{ByteOrder, LittleEndian};
-use std::io::{ErrorKind, Error};
+use std::io::Error;
 use std::fs::File;
 
 pub fn read_u64(f: &mut File) -> u64 {
@@ -57,6 +57,8 @@ pub fn get_matches_from_cli(arg_matches: &mut ArgMatches) {
                             .help("Path to input file to be chunked")
                             .takes_value(true)
                             .required(true))
+                    .args(&chunk_writing_args())
+                    .args(&remote_args())
                         )
         .subcommand(SubCommand::with_name("extract")
                     .help("Assembles chunks to form output")
@@ -79,10 +81,48 @@ pub fn get_matches_from_cli(arg_matches: &mut ArgMatches) {
                             .long("si")
                             .help("Path to seed index file")
                             .takes_value(true))
+                    .arg(Arg::with_name("skip-matching")
+                            .long("skip-matching")
+                            .help("Skip chunks the output already holds, for writing to block devices"))
+                    .arg(Arg::with_name("in-place")
+                            .long("in-place")
+                            .conflicts_with("skip-matching")
+                            .help("Update the existing output in place, using it as seed"))
+                    .arg(Arg::with_name("resume")
+                            .long("resume")
+                            .conflicts_with_all(&["in-place", "skip-matching"])
+                            .help("Continue an interrupted extract, keeping the chunks already written"))
+                    .arg(Arg::with_name("concurrency")
+                            .short("n")
+                            .long("concurrency")
+                            .help("Number of workers fetching and writing chunks, defaults to number of CPUs")
+                            .takes_value(true))
+                    .arg(Arg::with_name("verify")
+                            .long("verify")
+                            .help("Check the output against the index once it is assembled"))
+                    .arg(Arg::with_name("repair")
+                            .long("repair")
+                            .requires("verify")
+                            .help("Fetch chunks which fail verification from the store again"))
+                    .arg(Arg::with_name("offset")
+                            .long("offset")
+                            .help("Extract only the bytes of the blob starting at this offset")
+                            .takes_value(true))
+                    .arg(Arg::with_name("length")
+                            .long("length")
+                            .help("Extract at most this many bytes, defaults to everything up to the end")
+                            .takes_value(true))
+                    .arg(Arg::with_name("stats-format")
+                            .long("stats-format")
+                            .help("Format of the report printed when done")
+                            .possible_values(&["text", "json"])
+                            .takes_value(true))
+                    .args(&chunk_reading_args())
+                    .args(&remote_args())
                     .arg(Arg::with_name("file")
                             .short("f")
                             .long("file")
-                            .help("Path to input file to be chunked")
+                            .help("Path to output file, or directory to unpack .caidx archives into")
                             .takes_value(true)
                             .required(true))
                         )
@@ -98,10 +138,179 @@ pub fn get_matches_from_cli(arg_matches: &mut ArgMatches) {
                                 .long("file")
                                 .help("Path to input file")
                                 .takes_value(true))
+                        .arg(Arg::with_name("digest")
+                                .long("digest")
+                                .help("Hash for chunk ids of an input file, sha512-256 (default) or sha256")
+                                .possible_values(&["sha512-256", "sha256"])
+                                .takes_value(true))
+                        .args(&remote_args())
                         .group(ArgGroup::with_name("either_of_args")
                                 .args(&["index", "file"])
                                 .required(true))                     
                         )
+        .subcommand(SubCommand::with_name("tar")
+                    .help("Archives a directory tree in catar format and chunks it, writing a .caidx index")
+                    .arg(Arg::with_name("index")
+                            .short("i")
+                            .long("index")
+                            .help("Path to index file")
+                            .takes_value(true))
+                    .arg(Arg::with_name("store")
+                            .short("s")
+                            .long("store")
+                            .help("Path to chunk store")
+                            .takes_value(true))
+                    .arg(Arg::with_name("dir")
+                            .short("d")
+                            .long("dir")
+                            .help("Path to directory to archive")
+                            .takes_value(true)
+                            .required(true))
+                    .args(&chunk_writing_args())
+                    .args(&remote_args())
+                        )
+        .subcommand(SubCommand::with_name("untar")
+                    .help("Restores a directory tree from a .caidx index and its chunks")
+                    .arg(Arg::with_name("index")
+                            .short("i")
+                            .long("index")
+                            .help("Path to index file")
+                            .takes_value(true)
+                            .required(true))
+                    .arg(Arg::with_name("store")
+                            .short("s")
+                            .long("store")
+                            .help("Path to chunk store")
+                            .takes_value(true))
+                    .arg(Arg::with_name("dir")
+                            .short("d")
+                            .long("dir")
+                            .help("Path to directory to restore into")
+                            .takes_value(true)
+                            .required(true))
+                    .args(&chunk_reading_args())
+                    .args(&remote_args())
+                        )
+        .subcommand(SubCommand::with_name("train-dictionary")
+                        .help("Trains a zstd dictionary from chunks of a local store and recompresses the store with it")
+                        .arg(Arg::with_name("store")
+                                .short("s")
+                                .long("store")
+                                .help("Path to local store")
+                                .takes_value(true)
+                                .required(true))
+                        .arg(Arg::with_name("samples")
+                                .long("samples")
+                                .help("Number of chunks to train from, defaults to 1000")
+                                .takes_value(true))
+                        .arg(Arg::with_name("dictionary-size")
+                                .long("dictionary-size")
+                                .help("Maximum size of the dictionary in bytes, defaults to 112640")
+                                .takes_value(true))
+                        .arg(Arg::with_name("compression-level")
+                                .long("compression-level")
+                                .help("zstd level used to recompress chunks, 1 to 22, defaults to 21")
+                                .takes_value(true))
+                        .arg(Arg::with_name("encryption-key")
+                                .long("encryption-key")
+                                .help("File with the 32 byte key of a store keeping chunks encrypted")
+                                .takes_value(true))
+                        )
+        .subcommand(SubCommand::with_name("convert-store")
+                        .help("Converts a chunk store folder into a .capack pack file or back")
+                        .arg(Arg::with_name("from")
+                                .short("s")
+                                .long("from")
+                                .help("Path to store to read chunks from")
+                                .takes_value(true)
+                                .required(true))
+                        .arg(Arg::with_name("to")
+                                .short("t")
+                                .long("to")
+                                .help("Path to store to write chunks to")
+                                .takes_value(true)
+                                .required(true))
+                        .arg(Arg::with_name("compression-level")
+                                .long("compression-level")
+                                .help("zstd compression level recorded for chunks added to the pack later, defaults to 21")
+                                .takes_value(true)
+                                .conflicts_with("uncompressed"))
+                        .arg(Arg::with_name("uncompressed")
+                                .long("uncompressed")
+                                .help("Chunk store keeps chunks uncompressed"))
+                        .arg(Arg::with_name("encryption-key")
+                                .long("encryption-key")
+                                .help("File with the 32 byte key of a store keeping chunks encrypted")
+                                .takes_value(true))
+                        )
+        .subcommand(SubCommand::with_name("clean-store")
+                        .help("Removes temporary files left in a local store by interrupted writes, do not run while writing to the store")
+                        .arg(Arg::with_name("store")
+                                .short("s")
+                                .long("store")
+                                .help("Path to chunk store")
+                                .takes_value(true)
+                                .required(true))
+                        )
+        .subcommand(SubCommand::with_name("verify")
+                    .help("Checks a file against the chunks of its index")
+                    .arg(Arg::with_name("index")
+                            .short("i")
+                            .long("index")
+                            .help("Path to index file")
+                            .takes_value(true)
+                            .required(true))
+                    .arg(Arg::with_name("file")
+                            .short("f")
+                            .long("file")
+                            .help("Path to file or block device to check")
+                            .takes_value(true)
+                            .required(true))
+                    .arg(Arg::with_name("store")
+                            .short("s")
+                            .long("store")
+                            .help("Path to chunk store to repair from")
+                            .takes_value(true))
+                    .arg(Arg::with_name("repair")
+                            .long("repair")
+                            .requires("store")
+                            .help("Fetch bad chunks from the store and write them again"))
+                    .args(&chunk_reading_args())
+                    .arg(Arg::with_name("concurrency")
+                            .short("n")
+                            .long("concurrency")
+                            .help("Number of workers reading and hashing chunks, defaults to number of CPUs")
+                            .takes_value(true))
+                    .args(&remote_args())
+                        )
+        .subcommand(SubCommand::with_name("cat")
+                    .help("Prints the blob of an index, or part of it, straight from the chunk store")
+                    .arg(Arg::with_name("index")
+                            .short("i")
+                            .long("index")
+                            .help("Path to index file")
+                            .takes_value(true)
+                            .required(true))
+                    .arg(Arg::with_name("store")
+                            .short("s")
+                            .long("store")
+                            .help("Path to chunk store")
+                            .takes_value(true))
+                    .arg(Arg::with_name("offset")
+                            .long("offset")
+                            .help("Print only the bytes of the blob starting at this offset")
+                            .takes_value(true))
+                    .arg(Arg::with_name("length")
+                            .long("length")
+                            .help("Print at most this many bytes, defaults to everything up to the end")
+                            .takes_value(true))
+                    .arg(Arg::with_name("cache-size")
+                            .long("cache-size")
+                            .help("Number of decompressed chunks kept in memory, defaults to 16")
+                            .takes_value(true))
+                    .args(&chunk_reading_args())
+                    .args(&remote_args())
+                        )
         .subcommand(SubCommand::with_name("verify-index")
                         .help("Verifies a given index file against the input file")
                         .arg(Arg::with_name("index")
@@ -114,9 +323,144 @@ pub fn get_matches_from_cli(arg_matches: &mut ArgMatches) {
                                 .short("f")
                                 .long("file")
                                 .help("Path to input file")
+                                .takes_value(true)
                                 .required(true))
+                        .args(&remote_args())
                         ).get_matches();
 
         // TODO: Compare indexes and their correspondig sizes
         // TODO: Prune suuport
-}
\ No newline at end of file
+}
+
+// Options of commands chunking input into a store, make and tar
+fn chunk_writing_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("compression-level")
+            .long("compression-level")
+            .help("zstd compression level for new chunks, defaults to 21")
+            .takes_value(true)
+            .conflicts_with("uncompressed"),
+        Arg::with_name("uncompressed")
+            .long("uncompressed")
+            .help("Store chunks uncompressed, without .cacnk extension"),
+        Arg::with_name("encryption-key")
+            .long("encryption-key")
+            .help("File with the 32 byte key of a store keeping chunks encrypted")
+            .takes_value(true),
+        Arg::with_name("digest")
+            .long("digest")
+            .help("Hash for chunk ids, sha512-256 (default) or sha256")
+            .possible_values(&["sha512-256", "sha256"])
+            .takes_value(true),
+        Arg::with_name("concurrency")
+            .short("n")
+            .long("concurrency")
+            .help("Number of workers hashing, compressing and writing chunks, defaults to number of CPUs")
+            .takes_value(true),
+        Arg::with_name("stats-format")
+            .long("stats-format")
+            .help("Format of the report printed when done")
+            .possible_values(&["text", "json"])
+            .takes_value(true)
+    ]
+}
+
+// Options of commands reading chunks from a store
+fn chunk_reading_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("uncompressed")
+            .long("uncompressed")
+            .help("Chunk store keeps chunks uncompressed"),
+        Arg::with_name("encryption-key")
+            .long("encryption-key")
+            .help("File with the 32 byte key of a store keeping chunks encrypted")
+            .takes_value(true)
+    ]
+}
+
+// Options for reaching remote stores and indexes
+fn remote_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("ca-cert")
+            .long("ca-cert")
+            .help("Path to PEM bundle of CA certificates for https stores")
+            .takes_value(true),
+        Arg::with_name("client-cert")
+            .long("client-cert")
+            .help("Path to PEM client certificate for https stores")
+            .requires("client-key")
+            .takes_value(true),
+        Arg::with_name("client-key")
+            .long("client-key")
+            .help("Path to PEM (PKCS#8) client key for https stores")
+            .requires("client-cert")
+            .takes_value(true),
+        Arg::with_name("insecure-skip-verify")
+            .long("insecure-skip-verify")
+            .help("Do not verify TLS certificates of https stores or host keys of ssh stores"),
+        Arg::with_name("s3-endpoint")
+            .long("s3-endpoint")
+            .help("Endpoint of s3 stores, e.g. http://localhost:9000 for MinIO")
+            .takes_value(true),
+        Arg::with_name("s3-region")
+            .long("s3-region")
+            .help("Region of s3 stores, defaults to AWS_REGION or us-east-1")
+            .takes_value(true),
+        Arg::with_name("s3-credentials-file")
+            .long("s3-credentials-file")
+            .help("Credentials file of s3 stores like ~/.aws/credentials, defaults to AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, then ~/.aws/credentials")
+            .takes_value(true),
+        Arg::with_name("ssh-key")
+            .long("ssh-key")
+            .help("Path to private key for ssh stores, defaults to the ssh agent")
+            .takes_value(true),
+        Arg::with_name("header")
+            .short("H")
+            .long("header")
+            .help("Extra \"Name: value\" header for remote stores and indexes, can be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("basic-auth-env")
+            .long("basic-auth-env")
+            .conflicts_with_all(&["basic-auth-file", "bearer-token-env", "bearer-token-file"])
+            .help("Environment variable holding user:password for basic auth")
+            .takes_value(true),
+        Arg::with_name("basic-auth-file")
+            .long("basic-auth-file")
+            .conflicts_with_all(&["basic-auth-env", "bearer-token-env", "bearer-token-file"])
+            .help("File holding user:password for basic auth")
+            .takes_value(true),
+        Arg::with_name("bearer-token-env")
+            .long("bearer-token-env")
+            .conflicts_with_all(&["basic-auth-env", "basic-auth-file", "bearer-token-file"])
+            .help("Environment variable holding a bearer token")
+            .takes_value(true),
+        Arg::with_name("bearer-token-file")
+            .long("bearer-token-file")
+            .conflicts_with_all(&["basic-auth-env", "basic-auth-file", "bearer-token-env"])
+            .help("File holding a bearer token")
+            .takes_value(true),
+    ]
+}
+
+// Reads a credential from an environment variable or a file, whichever is given
+pub fn read_secret(env_var: Option<&str>, file: Option<&str>) -> Option<String> {
+    if let Some(var) = env_var {
+        match std::env::var(var) {
+            Ok(v) => Some(String::from(v.trim_end())),
+            Err(e) => {
+                panic!("Could not read credentials from environment variable {}, {:?}", var, e);
+            }
+        }
+    } else if let Some(path) = file {
+        match std::fs::read_to_string(path) {
+            Ok(v) => Some(String::from(v.trim_end())),
+            Err(e) => {
+                panic!("Could not read credentials from file {}, {:?}", path, e);
+            }
+        }
+    } else {
+        None
+    }
+}
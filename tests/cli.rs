@@ -0,0 +1,213 @@
+// Runs the desync-rs binary on files in a temporary directory
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+// Temporary working directory with the logging setup the binary expects
+fn workdir() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    fs::copy(Path::new(env!("CARGO_MANIFEST_DIR")).join("log4rs.yml"), dir.path().join("log4rs.yml")).unwrap();
+    dir
+}
+
+fn desync(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_desync-rs")).current_dir(dir).args(args).output().unwrap()
+}
+
+fn desync_ok(dir: &Path, args: &[&str]) -> Output {
+    let output = desync(dir, args);
+    assert!(output.status.success(), "desync-rs {:?} failed\n{}", args, String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+// Reproducible data which chunks at content defined boundaries
+fn random_bytes(size: usize, seed: u64) -> Vec<u8> {
+    let mut state: u64 = 0x9e3779b97f4a7c15 ^ seed;
+    (0..size).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 24) as u8
+    }).collect()
+}
+
+fn chunk_ids(listing: &Output) -> Vec<String> {
+    String::from_utf8_lossy(&listing.stdout).lines()
+        .filter_map(|l| l.split_whitespace().next())
+        .filter(|id| id.len() >= 40 && id.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(String::from)
+        .collect()
+}
+
+#[test]
+fn verify_index_and_list_chunks_use_the_index_digest() {
+    let dir = workdir();
+    let d = dir.path();
+    fs::write(d.join("input"), random_bytes(600_000, 1)).unwrap();
+    desync_ok(d, &["make", "-i", "sha256.caibx", "-s", "store", "-f", "input", "--digest", "sha256", "--compression-level", "1"]);
+
+    let verified = desync_ok(d, &["verify-index", "-i", "sha256.caibx", "-f", "input"]);
+    assert!(String::from_utf8_lossy(&verified.stdout).contains("matches"));
+
+    let from_index = chunk_ids(&desync_ok(d, &["list-chunks", "-i", "sha256.caibx"]));
+    let from_input = chunk_ids(&desync_ok(d, &["list-chunks", "-f", "input", "--digest", "sha256"]));
+    assert!(from_index.len() > 1);
+    assert_eq!(from_input, from_index);
+    let default_digest = chunk_ids(&desync_ok(d, &["list-chunks", "-f", "input"]));
+    assert_ne!(default_digest, from_index);
+
+    let mut changed = random_bytes(600_000, 1);
+    changed[300_000] ^= 0xff;
+    fs::write(d.join("input"), changed).unwrap();
+    assert!(!desync(d, &["verify-index", "-i", "sha256.caibx", "-f", "input"]).status.success());
+}
+
+// Every path below root with its type, permissions and contents or link target
+fn tree_listing(root: &Path) -> Vec<(String, String)> {
+    use std::os::unix::fs::MetadataExt;
+    let mut listing = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        let name = path.strip_prefix(root).unwrap().to_string_lossy().into_owned();
+        let description = if metadata.file_type().is_symlink() {
+            format!("link {:?}", fs::read_link(&path).unwrap())
+        } else if metadata.is_dir() {
+            for entry in fs::read_dir(&path).unwrap() {
+                pending.push(entry.unwrap().path());
+            }
+            format!("dir {:o}", metadata.mode() & 0o7777)
+        } else {
+            format!("file {:o} {:?} {}", metadata.mode() & 0o7777, fs::read(&path).unwrap(), metadata.mtime())
+        };
+        listing.push((name, description));
+    }
+    listing.sort();
+    listing
+}
+
+#[test]
+fn tar_and_untar_restore_the_tree() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = workdir();
+    let d = dir.path();
+    let tree = d.join("tree");
+    fs::create_dir_all(tree.join("sub/deeper")).unwrap();
+    fs::write(tree.join("big"), random_bytes(400_000, 2)).unwrap();
+    fs::write(tree.join("empty"), b"").unwrap();
+    fs::write(tree.join("sub/script"), b"#!/bin/sh\necho hi\n").unwrap();
+    fs::set_permissions(tree.join("sub/script"), fs::Permissions::from_mode(0o750)).unwrap();
+    fs::write(tree.join("sub/deeper/note"), b"note").unwrap();
+    std::os::unix::fs::symlink("sub/script", tree.join("link")).unwrap();
+
+    desync_ok(d, &["tar", "-i", "tree.caidx", "-s", "store", "-d", "tree", "--compression-level", "1", "-n", "2"]);
+    desync_ok(d, &["untar", "-i", "tree.caidx", "-s", "store", "-d", "restored"]);
+    assert_eq!(tree_listing(&d.join("restored")), tree_listing(&tree));
+}
+
+#[test]
+fn killed_extract_resumes_to_the_same_output() {
+    use std::time::{Duration, Instant};
+    let dir = workdir();
+    let d = dir.path();
+    let data = random_bytes(8_000_000, 3);
+    fs::write(d.join("input"), &data).unwrap();
+    desync_ok(d, &["make", "-i", "input.caibx", "-s", "store", "-f", "input", "--compression-level", "1"]);
+
+    // Killed once part of the output is written
+    let mut child = Command::new(env!("CARGO_BIN_EXE_desync-rs")).current_dir(d)
+        .args(["extract", "-i", "input.caibx", "-s", "store", "-f", "output", "-n", "1"])
+        .stdout(Stdio::null()).stderr(Stdio::null())
+        .spawn().unwrap();
+    let started = Instant::now();
+    while fs::metadata(d.join("output")).map_or(0, |m| m.len()) < 1_000_000 && started.elapsed() < Duration::from_secs(60) {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let written = fs::read(d.join("output")).unwrap();
+    if written == data {
+        // Done before it could be killed, cut it off mid chunk instead
+        fs::write(d.join("output"), &data[..4_321_000]).unwrap();
+    }
+    assert_ne!(fs::read(d.join("output")).unwrap(), data);
+
+    desync_ok(d, &["extract", "-i", "input.caibx", "-s", "store", "-f", "output", "--resume", "-n", "1"]);
+    assert!(fs::read(d.join("output")).unwrap() == data);
+
+    // Nothing left to do for a complete output, with or without workers
+    desync_ok(d, &["extract", "-i", "input.caibx", "-s", "store", "-f", "output", "--resume", "-n", "1"]);
+    assert!(fs::read(d.join("output")).unwrap() == data);
+    desync_ok(d, &["extract", "-i", "input.caibx", "-s", "store", "-f", "output", "--resume", "-n", "4"]);
+    assert!(fs::read(d.join("output")).unwrap() == data);
+}
+
+#[test]
+fn verify_repairs_damaged_chunks() {
+    let dir = workdir();
+    let d = dir.path();
+    let data = random_bytes(1_000_000, 4);
+    fs::write(d.join("input"), &data).unwrap();
+    desync_ok(d, &["make", "-i", "input.caibx", "-s", "store", "-f", "input", "--compression-level", "1"]);
+    desync_ok(d, &["extract", "-i", "input.caibx", "-s", "store", "-f", "output"]);
+
+    let mut damaged = data.clone();
+    damaged[10] ^= 0xff;
+    damaged[700_000] ^= 0xff;
+    fs::write(d.join("output"), &damaged).unwrap();
+    assert!(!desync(d, &["verify", "-i", "input.caibx", "-f", "output"]).status.success());
+
+    let repaired = desync_ok(d, &["verify", "-i", "input.caibx", "-s", "store", "-f", "output", "--repair"]);
+    assert!(String::from_utf8_lossy(&repaired.stdout).contains("Repaired 2 of"));
+    assert!(fs::read(d.join("output")).unwrap() == data);
+    desync_ok(d, &["verify", "-i", "input.caibx", "-f", "output"]);
+}
+
+#[test]
+fn extract_to_stdout_writes_only_the_blob() {
+    let dir = workdir();
+    let d = dir.path();
+    let data = random_bytes(500_000, 5);
+    fs::write(d.join("input"), &data).unwrap();
+    desync_ok(d, &["make", "-i", "input.caibx", "-s", "store", "-f", "input", "--compression-level", "1"]);
+    let extracted = desync_ok(d, &["extract", "-i", "input.caibx", "-s", "store", "-f", "-"]);
+    assert!(extracted.stdout == data);
+    assert!(String::from_utf8_lossy(&extracted.stderr).contains("extract stats"));
+}
+
+#[test]
+fn empty_file_makes_and_extracts() {
+    let dir = workdir();
+    let d = dir.path();
+    fs::write(d.join("input"), b"").unwrap();
+    let made = desync_ok(d, &["make", "-i", "empty.caibx", "-s", "store", "-f", "input", "--stats-format", "json"]);
+    assert!(String::from_utf8_lossy(&made.stdout).contains("\"total_chunks\":0"));
+    desync_ok(d, &["make", "-i", "parallel.caibx", "-s", "store", "-f", "input", "-n", "3"]);
+    assert_eq!(fs::read(d.join("parallel.caibx")).unwrap(), fs::read(d.join("empty.caibx")).unwrap());
+
+    fs::write(d.join("output"), b"stale").unwrap();
+    desync_ok(d, &["extract", "-i", "empty.caibx", "-s", "store", "-f", "output"]);
+    assert_eq!(fs::read(d.join("output")).unwrap(), b"");
+    desync_ok(d, &["verify-index", "-i", "empty.caibx", "-f", "input"]);
+}
+
+#[test]
+fn extract_reports_how_seed_bytes_were_copied() {
+    let dir = workdir();
+    let d = dir.path();
+    let data = random_bytes(500_000, 6);
+    fs::write(d.join("input"), &data).unwrap();
+    desync_ok(d, &["make", "-i", "input.caibx", "-s", "store", "-f", "input", "--compression-level", "1"]);
+    let extracted = desync_ok(d, &["extract", "-i", "input.caibx", "-s", "store", "-f", "output",
+        "--sf", "input", "--si", "input.caibx", "--stats-format", "json"]);
+    assert!(fs::read(d.join("output")).unwrap() == data);
+    let report = String::from_utf8_lossy(&extracted.stdout).to_string();
+    let value = |name: &str| -> u64 {
+        let key = format!("\"{}\":", name);
+        let rest = &report[report.find(&key).unwrap_or_else(|| panic!("{} missing from {}", name, report)) + key.len()..];
+        rest[..rest.find(|c: char| !c.is_ascii_digit()).unwrap()].parse().unwrap()
+    };
+    // Seed and output share the working directory's filesystem
+    assert!(value("seed_bytes_cloned") + value("seed_bytes_copied_in_kernel") > 0, "{}", report);
+    assert_eq!(value("seed_bytes_cloned") + value("seed_bytes_copied_in_kernel") + value("seed_bytes_copied"), value("bytes_from_seed"));
+}